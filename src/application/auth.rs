@@ -0,0 +1,298 @@
+use artisan_middleware::{
+    git_actions::{GitAuth, GitServer},
+    timestamp::current_timestamp,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{OnceLock, RwLock},
+    time::Duration,
+};
+
+use crate::config::{GitHubAppConfig, TokenFiles};
+
+// Tokens aren't re-read more often than this, however many repos fail at once
+const MIN_TOKEN_REFRESH_SECS: u64 = 60;
+
+// Installation tokens are valid for an hour, they are minted again this long before they run out
+const APP_TOKEN_LIFETIME_SECS: u64 = 3600;
+const APP_TOKEN_MARGIN_SECS: u64 = 600;
+
+// Tokens for each kind of git server
+struct ServerTokens {
+    github: Option<String>,
+    gitlab: Option<String>,
+    custom: Option<String>,
+    fetched_at: u64,
+}
+
+// Read the first time a token is needed, and again when the remote rejects it
+static SERVER_TOKENS: OnceLock<RwLock<ServerTokens>> = OnceLock::new();
+
+// token_files from the monitor config, set at startup and on reload
+static TOKEN_FILES: RwLock<TokenFiles> = RwLock::new(TokenFiles {
+    github: None,
+    gitlab: None,
+    custom: None,
+});
+
+// github_app from the monitor config and the installation token last minted for it
+static GITHUB_APP: RwLock<Option<GitHubAppConfig>> = RwLock::new(None);
+static APP_TOKEN: RwLock<Option<AppToken>> = RwLock::new(None);
+
+#[derive(Clone)]
+struct AppToken {
+    token: String,
+    expires_at: u64,
+}
+
+// Claims of the JWT the App signs to ask for an installation token
+#[derive(Serialize)]
+struct AppClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+    token: String,
+}
+
+// A NAME_FILE variable points at a file holding the token, so it can be rotated without a restart
+fn env_token(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| match std::env::var(format!("{}_FILE", name)) {
+            Ok(file) => std::fs::read_to_string(file).ok(),
+            Err(_) => std::env::var(name).ok(),
+        })
+        .map(|token| token.trim().to_string())
+        .find(|token| !token.is_empty())
+}
+
+// Point the tokens at new token files and re-read them right away
+pub fn set_token_files(files: &TokenFiles) {
+    if let Ok(mut token_files) = TOKEN_FILES.write() {
+        *token_files = files.clone();
+    }
+
+    if let Ok(mut tokens) = server_tokens().write() {
+        *tokens = read_tokens();
+    }
+}
+
+fn file_token(path: Option<String>) -> Option<String> {
+    let path = path?;
+    match std::fs::read_to_string(&path) {
+        Ok(token) => Some(token.trim().to_string()).filter(|token| !token.is_empty()),
+        Err(err) => {
+            log!(LogLevel::Warn, "Couldn't read the token file {}: {}", path, err);
+            None
+        }
+    }
+}
+
+// Each server's token file wins over its environment variables
+fn read_tokens() -> ServerTokens {
+    let files = TOKEN_FILES.read().map(|files| files.clone()).unwrap_or_default();
+    ServerTokens {
+        github: file_token(files.github).or_else(|| env_token(&["GITHUB_TOKEN", "GH_TOKEN"])),
+        gitlab: file_token(files.gitlab).or_else(|| env_token(&["GITLAB_TOKEN"])),
+        custom: file_token(files.custom).or_else(|| env_token(&["GIT_CUSTOM_TOKEN"])),
+        fetched_at: current_timestamp(),
+    }
+}
+
+fn server_tokens() -> &'static RwLock<ServerTokens> {
+    SERVER_TOKENS.get_or_init(|| RwLock::new(read_tokens()))
+}
+
+// Use a new GitHub App, the token of the previous one is dropped right away
+pub fn set_github_app(app: Option<GitHubAppConfig>) {
+    if let Ok(mut github_app) = GITHUB_APP.write() {
+        *github_app = app;
+    }
+
+    if let Ok(mut app_token) = APP_TOKEN.write() {
+        *app_token = None;
+    }
+}
+
+fn app_token() -> Option<String> {
+    APP_TOKEN.read().ok()?.as_ref().map(|app_token| app_token.token.clone())
+}
+
+// Mint a new installation token when none was minted yet or the current one is about to expire
+pub async fn refresh_app_token(force: bool) {
+    let app = match GITHUB_APP.read().ok().and_then(|app| app.clone()) {
+        Some(app) => app,
+        None => return,
+    };
+
+    let expires_at = APP_TOKEN.read().ok().and_then(|app_token| app_token.as_ref().map(|app_token| app_token.expires_at));
+    let now = current_timestamp();
+    if !force && expires_at.is_some_and(|expires_at| expires_at > now + APP_TOKEN_MARGIN_SECS) {
+        return;
+    }
+
+    match mint_installation_token(&app).await {
+        Ok(token) => {
+            log!(LogLevel::Debug, "Minted a token for GitHub App installation {}", app.installation_id);
+            if let Ok(mut app_token) = APP_TOKEN.write() {
+                *app_token = Some(AppToken {
+                    token,
+                    expires_at: now + APP_TOKEN_LIFETIME_SECS,
+                });
+            }
+        }
+        // the old token stays in use until it expires, the next pass tries again
+        Err(err) => log!(
+            LogLevel::Warn,
+            "Couldn't mint a token for GitHub App installation {}: {}",
+            app.installation_id,
+            err
+        ),
+    }
+}
+
+// Sign a short lived JWT with the App key and trade it for an installation token
+async fn mint_installation_token(app: &GitHubAppConfig) -> Result<String, ErrorArrayItem> {
+    let failed = |e: String| ErrorArrayItem::new(Errors::GeneralError, e);
+
+    let key = std::fs::read(&app.private_key_file)
+        .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("{}: {}", app.private_key_file, e)))?;
+    let key = EncodingKey::from_rsa_pem(&key).map_err(|e| failed(e.to_string()))?;
+
+    // backdated a minute against clock drift, GitHub refuses JWTs valid for more than ten minutes
+    let now = current_timestamp();
+    let claims = AppClaims {
+        iat: now.saturating_sub(60),
+        exp: now + 540,
+        iss: app.app_id.to_string(),
+    };
+    let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key).map_err(|e| failed(e.to_string()))?;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| failed(e.to_string()))?;
+
+    let response = client
+        .post(format!(
+            "{}/app/installations/{}/access_tokens",
+            app.api_url.trim_end_matches('/'),
+            app.installation_id
+        ))
+        .bearer_auth(jwt)
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", env!("CARGO_PKG_NAME"))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| failed(e.to_string()))?;
+
+    response
+        .json::<InstallationToken>()
+        .await
+        .map(|installation| installation.token)
+        .map_err(|e| failed(e.to_string()))
+}
+
+// Token for repos hosted on the given server
+pub fn token_for(server: &GitServer) -> Option<String> {
+    if let GitServer::GitHub = server {
+        if let Some(token) = app_token() {
+            return Some(token);
+        }
+    }
+
+    let tokens = server_tokens().read().ok()?;
+
+    match server {
+        GitServer::GitHub => tokens.github.clone(),
+        GitServer::GitLab => tokens.gitlab.clone(),
+        GitServer::Custom(_) => tokens.custom.clone(),
+    }
+}
+
+// Re-read the server tokens and mint a new App token, returns false when they were refreshed
+// too recently to try again
+pub async fn refresh_tokens() -> bool {
+    {
+        let mut tokens = match server_tokens().write() {
+            Ok(tokens) => tokens,
+            Err(_) => return false,
+        };
+
+        if current_timestamp().saturating_sub(tokens.fetched_at) < MIN_TOKEN_REFRESH_SECS {
+            return false;
+        }
+
+        log!(LogLevel::Info, "Remote rejected our credentials, reloading server tokens");
+        *tokens = read_tokens();
+    }
+
+    refresh_app_token(true).await;
+    true
+}
+
+// Whether git failed because the remote rejected the token
+pub fn is_auth_failure(err: &ErrorArrayItem) -> bool {
+    let message = err.to_string();
+    // git's own wording, a bare 401 would also match hashes, ports and paths
+    message.contains("Authentication failed")
+        || message.contains("The requested URL returned error: 401")
+        || message.contains("HTTP 401")
+        || message.contains("could not read Username")
+}
+
+// Token for a single repo, in order: its own token from the credentials file, the GitHub App
+// installation token for GitHub repos, the server's file from token_files, then the server's
+// environment variable (or the file its NAME_FILE variable names)
+pub fn resolve_token(auth: &GitAuth) -> Option<String> {
+    auth.token
+        .as_ref()
+        .map(|token| token.to_string().trim().to_string())
+        .filter(|token| !token.is_empty())
+        .or_else(|| token_for(&auth.server))
+}
+
+// Git config sending the token as a basic auth header, passed through the environment
+// so it never shows up in process listings or logged arguments (needs git 2.31)
+pub fn auth_env(server: &GitServer, token: &str) -> Vec<(&'static str, String)> {
+    let user = match server {
+        GitServer::GitHub => "x-access-token",
+        _ => "oauth2",
+    };
+    let credentials = STANDARD.encode(format!("{}:{}", user, token));
+
+    vec![
+        ("GIT_CONFIG_COUNT", String::from("1")),
+        ("GIT_CONFIG_KEY_0", String::from("http.extraheader")),
+        ("GIT_CONFIG_VALUE_0", format!("Authorization: Basic {}", credentials)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git_error(message: &str) -> ErrorArrayItem {
+        ErrorArrayItem::new(Errors::Git, String::from(message))
+    }
+
+    #[test]
+    fn only_git_auth_errors_count_as_auth_failures() {
+        assert!(is_auth_failure(&git_error(
+            "git fetch failed: fatal: unable to access 'https://github.com/example/assets.git/': The requested URL returned error: 401"
+        )));
+        assert!(is_auth_failure(&git_error("git fetch failed: remote: HTTP 401 Unauthorized")));
+        assert!(!is_auth_failure(&git_error("git checkout failed: pathspec 'a401f3c' did not match")));
+        assert!(!is_auth_failure(&git_error("git fetch failed: couldn't connect to git.example.com port 8401")));
+    }
+}
@@ -0,0 +1,195 @@
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    types::PathType,
+};
+use std::fmt;
+
+use crate::config::{GitVersionPolicy, MonitorConfig, RepoConfig};
+use crate::git::run_git;
+
+// Oldest git able to run `clone --mirror`, used by fetch only repos
+const FETCH_ONLY_MIN: GitVersion = GitVersion::new(1, 6, 0);
+// Oldest git with `status --porcelain`, used by the drift check
+const DRIFT_CHECK_MIN: GitVersion = GitVersion::new(1, 7, 0);
+// Oldest git with `tag --sort=-v:refname`, used by tag deploys
+const TAG_PATTERN_MIN: GitVersion = GitVersion::new(2, 0, 0);
+// Oldest git with `sparse-checkout set`, used by sparse checkouts
+const SPARSE_CHECKOUT_MIN: GitVersion = GitVersion::new(2, 25, 0);
+// Oldest git with `clone --filter=tree:0`, used by partial clones
+const PARTIAL_CLONE_MIN: GitVersion = GitVersion::new(2, 20, 0);
+// Oldest git with `worktree add`, used by shared worktrees
+const WORKTREE_MIN: GitVersion = GitVersion::new(2, 5, 0);
+// Oldest git with `verify-commit`, used by signature verification
+const VERIFY_COMMIT_MIN: GitVersion = GitVersion::new(2, 1, 0);
+// Oldest git reading config from GIT_CONFIG_COUNT, which carries the auth header
+const CONFIG_ENV_MIN: GitVersion = GitVersion::new(2, 31, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GitVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl GitVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self { major, minor, patch }
+    }
+
+    // Parse the output of `git --version`, e.g. "git version 2.39.2 (Apple Git-143)"
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output.split_whitespace().nth(2)?;
+        let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+
+        Some(Self {
+            major: parts.next()??,
+            minor: parts.next().flatten().unwrap_or(0),
+            patch: parts.next().flatten().unwrap_or(0),
+        })
+    }
+}
+
+impl fmt::Display for GitVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// Version of the installed git binary
+pub async fn detect_git_version() -> Result<GitVersion, ErrorArrayItem> {
+    let output = run_git(&PathType::Content(String::from("/")), &["--version"]).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    match GitVersion::parse(&stdout) {
+        Some(version) => {
+            log!(LogLevel::Info, "Using git {}", version);
+            Ok(version)
+        }
+        None => Err(ErrorArrayItem::new(
+            Errors::Git,
+            format!("Couldn't parse git version from: {}", stdout.trim()),
+        )),
+    }
+}
+
+// Whether the git-lfs extension is installed
+pub async fn detect_lfs() -> bool {
+    run_git(&PathType::Content(String::from("/")), &["lfs", "version"])
+        .await
+        .is_ok()
+}
+
+// Turn lfs off for repos asking for it when git-lfs is missing
+pub fn apply_lfs_availability(monitor: &mut MonitorConfig, lfs_installed: bool) {
+    if lfs_installed {
+        return;
+    }
+
+    for repo in monitor.repos.iter_mut().filter(|repo| repo.lfs) {
+        log!(
+            LogLevel::Warn,
+            "{}/{} asks for lfs but git-lfs isn't installed, pointer files won't be resolved",
+            repo.user,
+            repo.repo
+        );
+        repo.lfs = false;
+    }
+}
+
+fn skip_repo(repo: &mut RepoConfig, feature: String, version: &GitVersion) {
+    log!(LogLevel::Error, "Skipping {}/{}, installed git {} is too old: {}", repo.user, repo.repo, version, feature);
+    repo.unsupported = Some(format!("Skipped, installed git {} is too old: {}", version, feature));
+}
+
+// Disable features the installed git can't run, or refuse them all when the policy says so
+pub fn apply_git_version_policy(
+    monitor: &mut MonitorConfig,
+    version: &GitVersion,
+) -> Result<(), String> {
+    let refuse = monitor.git_version_policy == GitVersionPolicy::Refuse;
+    let mut unsupported: Vec<String> = Vec::new();
+
+    if *version < CONFIG_ENV_MIN {
+        // not a feature that can be turned off, tokens are simply not sent
+        log!(
+            LogLevel::Warn,
+            "Installed git {} can't take the auth header from the environment, tokens need git {}",
+            version,
+            CONFIG_ENV_MIN
+        );
+    }
+
+    if monitor.shared_worktrees && *version < WORKTREE_MIN {
+        unsupported.push(format!("shared_worktrees needs git {}", WORKTREE_MIN));
+        if !refuse {
+            monitor.shared_worktrees = false;
+        }
+    }
+
+    if monitor.drift_check.is_some() && *version < DRIFT_CHECK_MIN {
+        unsupported.push(format!("drift_check needs git {}", DRIFT_CHECK_MIN));
+        if !refuse {
+            monitor.drift_check = None;
+        }
+    }
+
+    for repo in monitor.repos.iter_mut() {
+        // without these a mirror would become a checkout and a tag pinned repo would deploy the branch
+        // tip, so the repo is skipped instead of degraded
+        if repo.fetch_only && *version < FETCH_ONLY_MIN {
+            let feature = format!("fetch_only on {}/{} needs git {}", repo.user, repo.repo, FETCH_ONLY_MIN);
+            if refuse {
+                unsupported.push(feature);
+            } else {
+                skip_repo(repo, feature, version);
+            }
+        }
+
+        if repo.tag_pattern.is_some() && *version < TAG_PATTERN_MIN {
+            let feature = format!("tag_pattern on {}/{} needs git {}", repo.user, repo.repo, TAG_PATTERN_MIN);
+            if refuse {
+                unsupported.push(feature);
+            } else {
+                skip_repo(repo, feature, version);
+            }
+        }
+
+        // turning verification off would accept unsigned updates, so it's always refused
+        if repo.verify_signature && *version < VERIFY_COMMIT_MIN {
+            return Err(format!(
+                "verify_signature on {}/{} needs git {}, installed git is {}",
+                repo.user, repo.repo, VERIFY_COMMIT_MIN, version
+            ));
+        }
+
+        if repo.clone_filter.is_some() && *version < PARTIAL_CLONE_MIN {
+            unsupported.push(format!("clone_filter on {}/{} needs git {}", repo.user, repo.repo, PARTIAL_CLONE_MIN));
+            if !refuse {
+                repo.clone_filter = None;
+            }
+        }
+
+        if !repo.sparse_paths.is_empty() && *version < SPARSE_CHECKOUT_MIN {
+            unsupported.push(format!("sparse_paths on {}/{} needs git {}", repo.user, repo.repo, SPARSE_CHECKOUT_MIN));
+            if !refuse {
+                repo.sparse_paths.clear();
+            }
+        }
+    }
+
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+
+    if refuse {
+        return Err(format!("Installed git {} is too old: {}", version, unsupported.join(", ")));
+    }
+
+    for feature in unsupported {
+        log!(LogLevel::Warn, "Disabled, installed git {} is too old: {}", version, feature);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use artisan_middleware::git_actions::{generate_git_project_id, GitAuth, GitCredentials, GitServer};
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use crate::config::MonitorConfig;
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, OpenOptions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+// Make sure every project path can be created and written, returns one line per problem.
+// Missing directories are only created with create set, dry runs and check mode just report
+pub fn validate_project_paths(entries: &[GitAuth], monitor: &MonitorConfig, create: bool) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    for git_item in entries {
+        let project_path = monitor.project_path(git_item).to_string();
+        let project_id = generate_git_project_id(git_item);
+
+        // existing checkouts are written in place, new ones are created inside the parent
+        let directory = if Path::new(&project_path).exists() {
+            Path::new(&project_path).to_path_buf()
+        } else {
+            match Path::new(&project_path).parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => {
+                    problems.push(format!("{}: {} has no parent directory", project_id, project_path));
+                    continue;
+                }
+            }
+        };
+
+        // without create (dry runs, check mode) a missing directory only has to be creatable
+        let directory = if create {
+            if let Err(err) = fs::create_dir_all(&directory) {
+                problems.push(format!("{}: can't create {}: {}", project_id, directory.display(), err));
+                continue;
+            }
+            directory
+        } else {
+            match directory.ancestors().find(|ancestor| ancestor.exists()) {
+                Some(existing) => existing.to_path_buf(),
+                None => {
+                    problems.push(format!("{}: none of the parents of {} exist", project_id, directory.display()));
+                    continue;
+                }
+            }
+        };
+
+        if let Err(err) = check_writable(&directory) {
+            problems.push(format!("{}: {} isn't writable: {}", project_id, directory.display(), err));
+        }
+    }
+
+    if problems.is_empty() {
+        log!(LogLevel::Trace, "All project paths are writable");
+    }
+
+    problems
+}
+
+// Entries sharing a project path would keep overwriting each other's checkout, returns one line per shared path
+pub fn duplicate_project_paths(entries: &[GitAuth], monitor: &MonitorConfig) -> Vec<String> {
+    let mut paths: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for git_item in entries {
+        paths
+            .entry(monitor.project_path(git_item).to_string())
+            .or_default()
+            .push(format!("{}-{}@{}", git_item.user, git_item.repo, git_item.branch));
+    }
+
+    paths
+        .into_iter()
+        .filter(|(_, entries)| entries.len() > 1)
+        .map(|(path, entries)| format!("{} is used by {}", path, entries.join(", ")))
+        .collect()
+}
+
+// Checkouts in the repo directories that don't belong to any configured repo
+pub fn orphaned_checkouts(git_credentials: &GitCredentials, monitor: &MonitorConfig) -> Vec<PathBuf> {
+    let project_paths: HashSet<PathBuf> = git_credentials
+        .clone()
+        .to_vec()
+        .iter()
+        .map(|git_item| PathBuf::from(monitor.project_path(git_item).to_string()))
+        .collect();
+    let roots: HashSet<PathBuf> = project_paths
+        .iter()
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect();
+
+    let mut orphans: Vec<PathBuf> = Vec::new();
+    for root in roots {
+        let entries = match fs::read_dir(&root) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log!(LogLevel::Warn, "Couldn't scan {} for orphaned checkouts: {}", root.display(), err);
+                continue;
+            }
+        };
+
+        // only git checkouts and mirrors are candidates, anything else in the root is left alone
+        orphans.extend(
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_dir() && !project_paths.contains(path))
+                .filter(|path| path.join(".git").exists() || path.join("HEAD").is_file()),
+        );
+    }
+
+    orphans
+}
+
+// Problems that make a single credentials entry unusable, the rest of the file is still monitored
+pub fn entry_problems(git_item: &GitAuth) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    for (field, value) in [
+        ("user", git_item.user.to_string()),
+        ("repo", git_item.repo.to_string()),
+        ("branch", git_item.branch.to_string()),
+    ] {
+        if value.trim().is_empty() {
+            problems.push(format!("{} is empty", field));
+        }
+    }
+
+    if let GitServer::Custom(host) = &git_item.server {
+        if host.trim().is_empty() {
+            problems.push(String::from("custom server has no host"));
+        }
+    }
+
+    // a token git can't send would fail every fetch and keep the repo in backoff forever
+    if let Some(token) = &git_item.token {
+        let token = token.to_string();
+        if token.trim().is_empty() {
+            problems.push(String::from("token is empty"));
+        } else if token.trim().chars().any(|c| c.is_whitespace() || c.is_control()) {
+            problems.push(String::from("token contains whitespace or control characters"));
+        }
+    }
+
+    problems
+}
+
+// The configured repo root has to exist already, it's usually a separately mounted volume
+pub fn validate_repo_root(root: &str) -> Result<(), String> {
+    let root = Path::new(root);
+    if !root.is_dir() {
+        return Err(format!("repo_root {} doesn't exist or isn't a directory", root.display()));
+    }
+
+    check_writable(root).map_err(|err| format!("repo_root {} isn't writable: {}", root.display(), err))
+}
+
+// The git binary has to be an executable file, either at the given path or somewhere on PATH
+pub fn validate_git_binary(binary: &str) -> Result<(), String> {
+    let candidates: Vec<PathBuf> = if binary.contains('/') {
+        vec![PathBuf::from(binary)]
+    } else {
+        std::env::var_os("PATH")
+            .map(|path| std::env::split_paths(&path).map(|dir| dir.join(binary)).collect())
+            .unwrap_or_default()
+    };
+
+    let executable = candidates.iter().any(|candidate| {
+        fs::metadata(candidate)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    });
+
+    if executable {
+        Ok(())
+    } else {
+        Err(format!("git_binary {} wasn't found or isn't executable", binary))
+    }
+}
+
+// Create and remove a probe file, the only reliable way to test access as the service user
+fn check_writable(directory: &Path) -> std::io::Result<()> {
+    let probe = directory.join(".ais_gitmon_probe");
+    OpenOptions::new().create(true).truncate(true).write(true).open(&probe)?;
+    fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dusa_collection_utils::stringy::Stringy;
+
+    fn test_auth(repo: &str, token: Option<&str>) -> GitAuth {
+        GitAuth {
+            user: Stringy::from("example"),
+            repo: Stringy::from(repo),
+            branch: Stringy::from("main"),
+            token: token.map(|token| token.to_string().into()),
+            server: GitServer::GitHub,
+        }
+    }
+
+    #[test]
+    fn colliding_entries_are_reported_once() {
+        let monitor = MonitorConfig {
+            repo_root: Some(String::from("/srv/repos")),
+            ..MonitorConfig::default()
+        };
+        // the same repo entered twice, only the token differs
+        let entries = vec![
+            test_auth("assets", Some("first-token")),
+            test_auth("assets", Some("second-token")),
+            test_auth("docs", None),
+        ];
+
+        let conflicts = duplicate_project_paths(&entries, &monitor);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].matches("example-assets@main").count(), 2);
+        assert!(!conflicts[0].contains("docs"));
+    }
+
+    #[test]
+    fn report_only_leaves_missing_directories_alone() {
+        let root = std::env::temp_dir().join(format!("ais_gitmon_paths_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let missing = root.join("not").join("yet");
+        let monitor = MonitorConfig {
+            repo_root: Some(missing.to_string_lossy().to_string()),
+            ..MonitorConfig::default()
+        };
+        let entries = vec![test_auth("assets", None)];
+
+        assert!(validate_project_paths(&entries, &monitor, false).is_empty());
+        assert!(!missing.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,8 +1,501 @@
 use artisan_middleware::config::AppConfig;
+use artisan_middleware::git_actions::{generate_git_project_path, GitAuth};
 use artisan_middleware::version::{aml_version, str_to_version};
+use ::config::{Config, ConfigError, File};
 use dusa_collection_utils::version::{SoftwareVersion, Version, VersionCode};
-use dusa_collection_utils::{log, stringy::Stringy};
+use dusa_collection_utils::{log, stringy::Stringy, types::PathType};
 use dusa_collection_utils::log::LogLevel;
+use serde::Deserialize;
+use std::path::Path;
+
+// Gitmonitor specific settings, read from the [monitor] table of the config files
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MonitorConfig {
+    // Visit the repos host by host instead of in a fully shuffled order
+    pub host_batched_fetch: bool,
+    // Record every deploy into a separate git repo
+    pub deploy_ledger: Option<LedgerConfig>,
+    // Check the working trees for out of band changes, disabled when unset
+    pub drift_check: Option<DriftPolicy>,
+    // Pull the repo list from an inventory service instead of the credentials file
+    pub remote_credentials: Option<RemoteCredentialsConfig>,
+    // Updates made only of commits containing one of these are pulled without being reported as deploys
+    pub skip_deploy_patterns: Vec<String>,
+    // What to do when a configured feature needs a newer git than the one installed
+    pub git_version_policy: GitVersionPolicy,
+    // Identity used for commits made by the monitor, defaults to GitMonitor <gitmonitor@hostname>
+    pub commit_author_name: Option<String>,
+    pub commit_author_email: Option<String>,
+    // Run git fsck on every repo this often, disabled when unset
+    pub fsck_interval_secs: Option<u64>,
+    // Delete and clone again repos failing fsck
+    pub fsck_reclone: bool,
+    // Also run fsck once a repo failed this many passes in a row, a corrupted checkout never recovers on its own
+    pub fsck_after_failures: Option<u32>,
+    // A repo whose checkout had to be cloned again this many times within reclone_window_secs is quarantined
+    // until resumed through the control socket or a restart, 3 times in 86400 seconds when unset
+    pub reclone_limit: Option<u32>,
+    pub reclone_window_secs: Option<u64>,
+    // Poll quickly after startup and updates, slowing down while repos stay up to date
+    pub adaptive_schedule: Option<AdaptiveSchedule>,
+    // What to do with uncommitted changes to tracked files when an update comes in
+    pub local_changes: LocalChangesPolicy,
+    // What to do when the checkout has a detached HEAD or commits upstream doesn't have
+    pub divergence: DivergencePolicy,
+    // What to do when the tracked branch was deleted upstream
+    pub branch_deleted: BranchDeletedPolicy,
+    // Branch followed instead under the fallback_branch policy, main when unset
+    pub fallback_branch: Option<String>,
+    // Serve the current state as json on this address, e.g. 127.0.0.1:8085
+    pub status_bind: Option<String>,
+    // Serve prometheus metrics on this address, e.g. 127.0.0.1:9185
+    pub metrics_bind: Option<String>,
+    // Remove checkouts whose origin isn't the configured repo so they get cloned again
+    pub repair_foreign_remote: bool,
+    // User the cloned repos are handed over to, www-data when unset
+    pub owner: Option<String>,
+    // Fetch and report but never clone, pull or reset, also enabled by --dry-run
+    pub dry_run: bool,
+    // Git commands running longer than this are killed, 120 seconds when unset
+    pub git_timeout_secs: Option<u64>,
+    // Same for commands talking to the remote (clone, fetch, pull), 600 seconds when unset
+    pub fetch_timeout_secs: Option<u64>,
+    // Git executable, a name looked up on PATH or a full path, "git" when unset
+    // Every git command of the monitor runs it, only cli_credential's remote check uses the git on PATH
+    pub git_binary: Option<String>,
+    // How long a shutdown waits for the repo being worked on before killing its git commands, 30 when unset
+    pub shutdown_grace_secs: Option<u64>,
+    // Lock files left by a killed git are removed once older than this, 600 seconds when unset
+    pub stale_lock_secs: Option<u64>,
+    // Write repo events as json lines instead of free form log messages
+    pub json_logs: bool,
+    // Routine state updates are written at most this often, 60 seconds when unset
+    pub state_write_interval_secs: Option<u64>,
+    // Directory the repos are cloned into instead of the default location
+    pub repo_root: Option<String>,
+    // Delete checkouts of repos no longer in the credentials at startup instead of only warning
+    pub prune: bool,
+    // Check out further branches of an already cloned repo as worktrees of that clone
+    pub shared_worktrees: bool,
+    // Accept reload, status, pull, pause and resume commands on this unix socket
+    pub control_socket: Option<String>,
+    // Proxy for all outbound http(s) traffic, including git and the inventory and webhook requests
+    pub http_proxy: Option<String>,
+    // Comma separated hosts reached without the proxy
+    pub no_proxy: Option<String>,
+    // GNUPGHOME holding the keys trusted to sign commits of repos with verify_signature
+    pub signing_keyring: Option<String>,
+    // Add a single global safe.directory=* entry instead of one entry per repo
+    pub safe_directory_wildcard: bool,
+    // Errors kept in the state file, the oldest are dropped first, 50 when unset
+    pub error_log_size: Option<usize>,
+    // Files holding the token of each kind of server, e.g. mounted secrets, preferred over the environment
+    pub token_files: TokenFiles,
+    // Authenticate to GitHub as an App installation instead of with a personal token
+    pub github_app: Option<GitHubAppConfig>,
+    // Main loop sleep and poll jitter
+    pub timing: LoopTiming,
+    // How git copes with slow or flaky connections to the remote
+    pub http: HttpTuning,
+    // Per repo settings
+    pub repos: Vec<RepoConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoopTiming {
+    // The main loop sleeps a random time between these between passes
+    pub tick_min_secs: u64,
+    pub tick_max_secs: u64,
+    // Spread applied around per repo poll intervals so repos don't all fetch at once
+    pub poll_jitter_secs: u64,
+}
+
+impl Default for LoopTiming {
+    fn default() -> Self {
+        Self {
+            tick_min_secs: 30,
+            tick_max_secs: 30,
+            poll_jitter_secs: 5,
+        }
+    }
+}
+
+// Retries happen within a single pass, only once they are used up does the repo count a failure
+// and go into its backoff
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HttpTuning {
+    // Transfers slower than low_speed_limit bytes per second for low_speed_time_secs are aborted,
+    // git's own defaults when unset
+    pub low_speed_limit: Option<u64>,
+    pub low_speed_time_secs: Option<u64>,
+    // Network commands failing with a 5xx, a dropped connection or a too slow transfer are run again this often
+    pub retries: u32,
+    // User-Agent git sends over http(s), git's own when unset
+    pub user_agent: Option<String>,
+    // "Name: value" headers added to every http(s) request git makes, e.g. for a proxy that wants them
+    pub extra_headers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdaptiveSchedule {
+    // Interval used after startup and after an update
+    #[serde(default = "default_adaptive_min")]
+    pub min_secs: u64,
+    // Upper bound the interval grows to
+    #[serde(default = "default_adaptive_max")]
+    pub max_secs: u64,
+    // Growth applied after every up to date pass
+    #[serde(default = "default_adaptive_multiplier")]
+    pub multiplier: u64,
+}
+
+fn default_adaptive_min() -> u64 {
+    10
+}
+
+fn default_adaptive_max() -> u64 {
+    300
+}
+
+fn default_adaptive_multiplier() -> u64 {
+    2
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalChangesPolicy {
+    // Leave the checkout alone and log a warning
+    #[default]
+    Skip,
+    // Stash the changes, pull, then pop them back
+    Stash,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DivergencePolicy {
+    // Log a warning and pull anyway
+    #[default]
+    Warn,
+    // Leave the checkout alone and report an error
+    Refuse,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchDeletedPolicy {
+    // Keep the checkout as it is and warn once
+    #[default]
+    WarnAndHold,
+    // Remove the checkout, it is cloned again once the branch is back upstream
+    DeleteLocal,
+    // Follow the fallback branch instead
+    FallbackBranch,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GitVersionPolicy {
+    // Warn and turn the feature off
+    #[default]
+    Disable,
+    // Refuse to start
+    Refuse,
+}
+
+// Settings for a single credentials entry, matched by user, repo and optionally branch
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RepoConfig {
+    pub user: String,
+    pub repo: String,
+    pub branch: Option<String>,
+    // Keep a bare mirror of the repo without ever checking out a working tree
+    pub fetch_only: bool,
+    // Clone from this url instead of the one built from the credentials
+    pub clone_url: Option<String>,
+    // Fetch and pull from this url, may use a different protocol than the clone
+    pub fetch_url: Option<String>,
+    // Deploy the highest version tag matching this glob instead of the branch tip
+    pub tag_pattern: Option<String>,
+    // Poll this repo on its own interval, jittered by a few seconds
+    pub poll_interval_secs: Option<u64>,
+    // Initialize and update submodules along with the checkout
+    pub submodules: bool,
+    // Fetch git lfs objects after every checkout, needs git-lfs installed
+    pub lfs: bool,
+    // Clone and fetch over ssh with this deploy key instead of https
+    pub ssh_key: Option<String>,
+    // Shell command run after new commits were pulled, gets GITMON_REPO_PATH and GITMON_COMMIT
+    pub on_update: Option<String>,
+    // Url receiving a json POST after new commits were pulled
+    pub on_update_url: Option<String>,
+    // How pulls integrate upstream commits, rebase when unset
+    pub pull_strategy: PullStrategy,
+    // Only check out these directories, the whole tree when empty
+    pub sparse_paths: Vec<String>,
+    // Only pull when the new upstream tip carries a valid signature from the signing keyring
+    pub verify_signature: bool,
+    // Clone without historic blobs or trees, git fetches them when they're needed
+    pub clone_filter: Option<CloneFilter>,
+    // Updates touching only paths matching these globs are pulled without reporting a deploy
+    pub ignore_paths: Vec<String>,
+    // Remote the branch is tracked on, e.g. upstream for mirrored setups, origin when unset
+    pub remote: Option<String>,
+    // Log level while this repo is being worked on, the global level otherwise
+    pub log_level: Option<LogLevel>,
+    // Repos of a group are updated in ascending order, a failing repo holds back the ones after it
+    pub group: Option<String>,
+    pub order: i32,
+    // Only ever fast forward the checkout, refusing anything that would reset local only commits or state
+    pub protect_local: bool,
+    // Why the installed git can't run this repo's settings, the repo is skipped while set
+    #[serde(skip)]
+    pub unsupported: Option<String>,
+}
+
+const DEFAULT_REMOTE: &str = "origin";
+
+impl RepoConfig {
+    pub fn remote(&self) -> &str {
+        self.remote.as_deref().unwrap_or(DEFAULT_REMOTE)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloneFilter {
+    Blobless,
+    Treeless,
+}
+
+impl CloneFilter {
+    pub fn flag(&self) -> &'static str {
+        match self {
+            CloneFilter::Blobless => "--filter=blob:none",
+            CloneFilter::Treeless => "--filter=tree:0",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PullStrategy {
+    #[default]
+    Rebase,
+    Merge,
+    // Fail instead of creating a merge or rebasing local commits
+    FfOnly,
+}
+
+impl PullStrategy {
+    pub fn flag(&self) -> &'static str {
+        match self {
+            PullStrategy::Rebase => "--rebase",
+            PullStrategy::Merge => "--no-rebase",
+            PullStrategy::FfOnly => "--ff-only",
+        }
+    }
+}
+
+const DEFAULT_GIT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_FETCH_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_GIT_BINARY: &str = "git";
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+const DEFAULT_STALE_LOCK_SECS: u64 = 600;
+const DEFAULT_FALLBACK_BRANCH: &str = "main";
+const DEFAULT_STATE_WRITE_INTERVAL_SECS: u64 = 60;
+const DEFAULT_ERROR_LOG_SIZE: usize = 50;
+const DEFAULT_RECLONE_LIMIT: u32 = 3;
+const DEFAULT_RECLONE_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+impl MonitorConfig {
+    pub fn owner(&self) -> &str {
+        self.owner.as_deref().unwrap_or("www-data")
+    }
+
+    // Checkout location of a repo, moved under repo_root when one is set
+    pub fn project_path(&self, auth: &GitAuth) -> PathType {
+        let default_path = generate_git_project_path(auth);
+        let root = match &self.repo_root {
+            Some(root) => root,
+            None => return default_path,
+        };
+
+        let default_path = default_path.to_string();
+        match Path::new(&default_path).file_name() {
+            Some(name) => PathType::Content(Path::new(root).join(name).to_string_lossy().to_string()),
+            None => PathType::Content(default_path),
+        }
+    }
+
+    pub fn git_timeout_secs(&self) -> u64 {
+        self.git_timeout_secs.unwrap_or(DEFAULT_GIT_TIMEOUT_SECS)
+    }
+
+    pub fn fetch_timeout_secs(&self) -> u64 {
+        self.fetch_timeout_secs.unwrap_or(DEFAULT_FETCH_TIMEOUT_SECS)
+    }
+
+    pub fn shutdown_grace_secs(&self) -> u64 {
+        self.shutdown_grace_secs.unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS)
+    }
+
+    pub fn fallback_branch(&self) -> &str {
+        self.fallback_branch.as_deref().unwrap_or(DEFAULT_FALLBACK_BRANCH)
+    }
+
+    pub fn stale_lock_secs(&self) -> u64 {
+        self.stale_lock_secs.unwrap_or(DEFAULT_STALE_LOCK_SECS)
+    }
+
+    pub fn git_binary(&self) -> &str {
+        self.git_binary.as_deref().unwrap_or(DEFAULT_GIT_BINARY)
+    }
+
+    pub fn error_log_size(&self) -> usize {
+        self.error_log_size.unwrap_or(DEFAULT_ERROR_LOG_SIZE)
+    }
+
+    pub fn reclone_limit(&self) -> u32 {
+        self.reclone_limit.unwrap_or(DEFAULT_RECLONE_LIMIT)
+    }
+
+    pub fn reclone_window_secs(&self) -> u64 {
+        self.reclone_window_secs.unwrap_or(DEFAULT_RECLONE_WINDOW_SECS)
+    }
+
+    pub fn state_write_interval_secs(&self) -> u64 {
+        self.state_write_interval_secs
+            .unwrap_or(DEFAULT_STATE_WRITE_INTERVAL_SECS)
+    }
+
+    // Bounds of the main loop sleep, capped to serve the most frequently polled repo
+    pub fn tick_range(&self) -> (u64, u64) {
+        let mut tick_max = self.timing.tick_max_secs;
+        if let Some(schedule) = &self.adaptive_schedule {
+            tick_max = tick_max.min(schedule.min_secs);
+        }
+        for repo in &self.repos {
+            if let Some(interval) = repo.poll_interval_secs {
+                tick_max = tick_max.min(interval.saturating_sub(self.timing.poll_jitter_secs));
+            }
+        }
+
+        let tick_max = tick_max.max(1);
+        (self.timing.tick_min_secs.clamp(1, tick_max), tick_max)
+    }
+
+    // Name and email to commit with
+    pub fn commit_author(&self) -> (String, String) {
+        let name = self
+            .commit_author_name
+            .clone()
+            .unwrap_or_else(|| String::from("GitMonitor"));
+        let email = self.commit_author_email.clone().unwrap_or_else(|| {
+            let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|hostname| hostname.trim().to_string())
+                .unwrap_or_else(|_| String::from("localhost"));
+            format!("gitmonitor@{}", hostname)
+        });
+
+        (name, email)
+    }
+
+    // Settings for the given entry, defaults when it has none
+    pub fn repo_config(&self, auth: &GitAuth) -> RepoConfig {
+        self.repos
+            .iter()
+            .find(|repo| {
+                repo.user == auth.user.to_string()
+                    && repo.repo == auth.repo.to_string()
+                    && repo
+                        .branch
+                        .as_ref()
+                        .is_none_or(|branch| *branch == auth.branch.to_string())
+            })
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+// A token only ever goes to the kind of server its file is configured for
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TokenFiles {
+    pub github: Option<String>,
+    pub gitlab: Option<String>,
+    // Sent to every custom server
+    pub custom: Option<String>,
+}
+
+// GitHub App whose installation tokens are used for GitHub repos without a token of their own
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitHubAppConfig {
+    pub app_id: u64,
+    pub installation_id: u64,
+    // PEM private key generated for the App
+    pub private_key_file: String,
+    // GitHub Enterprise servers serve the API under their own host
+    #[serde(default = "default_github_api_url")]
+    pub api_url: String,
+}
+
+fn default_github_api_url() -> String {
+    String::from("https://api.github.com")
+}
+
+// Http endpoint serving the repo list as json
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteCredentialsConfig {
+    pub url: String,
+    // Sent as a bearer token when set
+    pub token: Option<String>,
+    // Last good copy of the list, used when the endpoint is unreachable
+    pub cache_file: String,
+    #[serde(default = "default_remote_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_remote_timeout() -> u64 {
+    30
+}
+
+// What to do when a working tree no longer matches HEAD
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+    // Only report the drift
+    Report,
+    // Report the drift then reset the tree back to HEAD
+    Reset,
+}
+
+// Repo receiving a line for every deploy made by the monitor
+#[derive(Debug, Clone, Deserialize)]
+pub struct LedgerConfig {
+    pub remote_url: String,
+    // Sent to an https remote, the token of the server it's hosted on when unset
+    pub token: Option<String>,
+    #[serde(default = "default_ledger_branch")]
+    pub branch: String,
+    // Local checkout of the ledger repo
+    pub directory: String,
+    // Ledger file inside the repo, one json object per line
+    #[serde(default = "default_ledger_file")]
+    pub file: String,
+}
+
+fn default_ledger_branch() -> String {
+    String::from("main")
+}
+
+fn default_ledger_file() -> String {
+    String::from("deployments.jsonl")
+}
 
 pub fn get_config() -> AppConfig {
     let mut config: AppConfig = match AppConfig::new() {
@@ -13,16 +506,7 @@ pub fn get_config() -> AppConfig {
         }
     };
 
-    let raw_version: SoftwareVersion = {
-        // defining the version
-        let library_version: Version = aml_version();
-        let software_version: Version = str_to_version(env!("CARGO_PKG_VERSION"), Some(VersionCode::Production));
-        
-        SoftwareVersion {
-            application: software_version,
-            library: library_version,
-        }
-    };
+    let raw_version: SoftwareVersion = software_version();
 
     config.version = match serde_json::to_string(&raw_version) {
         Ok(ver) => ver,
@@ -35,4 +519,42 @@ pub fn get_config() -> AppConfig {
     config.app_name = Stringy::from(env!("CARGO_PKG_NAME"));
     config.database = None;
     config
-}
\ No newline at end of file
+}
+
+// The application and middleware library versions this binary was built with
+pub fn software_version() -> SoftwareVersion {
+    let library_version: Version = aml_version();
+    let software_version: Version = str_to_version(env!("CARGO_PKG_VERSION"), Some(VersionCode::Production));
+
+    SoftwareVersion {
+        application: software_version,
+        library: library_version,
+    }
+}
+
+pub fn get_monitor_config() -> MonitorConfig {
+    let settings: Result<Config, ConfigError> = Config::builder()
+        .add_source(File::with_name("Config").required(false))
+        .add_source(File::with_name("Overrides").required(false))
+        .build();
+
+    let monitor = settings.and_then(|settings| settings.get::<MonitorConfig>("monitor"));
+
+    match monitor {
+        Ok(loaded_data) if loaded_data.timing.tick_min_secs > loaded_data.timing.tick_max_secs => {
+            log!(
+                LogLevel::Error,
+                "Invalid monitor config: timing.tick_min_secs {} is above timing.tick_max_secs {}",
+                loaded_data.timing.tick_min_secs,
+                loaded_data.timing.tick_max_secs
+            );
+            std::process::exit(0)
+        }
+        Ok(loaded_data) => loaded_data,
+        Err(ConfigError::NotFound(_)) => MonitorConfig::default(),
+        Err(e) => {
+            log!(LogLevel::Error, "Couldn't load monitor config: {}", e.to_string());
+            std::process::exit(0)
+        }
+    }
+}
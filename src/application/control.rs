@@ -0,0 +1,154 @@
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixListener,
+};
+
+use crate::runtime::RepoRuntimes;
+use crate::status::SharedDocument;
+
+// Repo commands received on the control socket, applied by the main loop before its next pass
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Pull(String),
+    Pause(String),
+    Resume(String),
+}
+
+pub type ControlQueue = Arc<Mutex<Vec<ControlCommand>>>;
+
+// Accept one command per line: reload, status, pull <id>, pause <id>, resume <id>
+pub fn spawn_control_socket(
+    socket_path: String,
+    reload: Arc<AtomicBool>,
+    status: SharedDocument,
+    queue: ControlQueue,
+) {
+    tokio::spawn(async move {
+        // a socket left behind by an earlier run would make the bind fail
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = match UnixListener::bind(&socket_path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                log!(LogLevel::Error, "Couldn't bind control socket {}: {}", socket_path, err);
+                return;
+            }
+        };
+        log!(LogLevel::Info, "Control socket listening on {}", socket_path);
+
+        loop {
+            let (socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log!(LogLevel::Warn, "Control socket accept failed: {}", err);
+                    continue;
+                }
+            };
+
+            let reload = reload.clone();
+            let status = status.clone();
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = socket.into_split();
+                let mut lines = BufReader::new(reader).lines();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let response = handle_command(line.trim(), &reload, &status, &queue);
+                    if writer.write_all(format!("{}\n", response).as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn handle_command(line: &str, reload: &AtomicBool, status: &SharedDocument, queue: &ControlQueue) -> String {
+    let (command, argument) = match line.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, Some(argument.trim().to_string())),
+        None => (line, None),
+    };
+
+    let queued = match (command, argument) {
+        ("reload", None) => {
+            reload.store(true, Ordering::Relaxed);
+            return String::from("ok");
+        }
+        ("status", None) => {
+            return match status.lock() {
+                Ok(current) => current.clone(),
+                Err(_) => String::from("error: status unavailable"),
+            };
+        }
+        ("pull", Some(id)) => ControlCommand::Pull(id),
+        ("pause", Some(id)) => ControlCommand::Pause(id),
+        ("resume", Some(id)) => ControlCommand::Resume(id),
+        _ => return format!("error: unknown command {}", line),
+    };
+
+    match queue.lock() {
+        Ok(mut queue) => {
+            queue.push(queued);
+            String::from("queued")
+        }
+        Err(_) => String::from("error: queue unavailable"),
+    }
+}
+
+// Whether commands are waiting, the main loop cuts its sleep short for them
+pub fn has_pending(queue: &ControlQueue) -> bool {
+    queue.lock().map(|queue| !queue.is_empty()).unwrap_or(false)
+}
+
+// Make every repo due on the next pass, paused repos stay paused
+pub fn pull_all(runtimes: &mut RepoRuntimes) {
+    log!(LogLevel::Info, "Pulling all repos on request");
+    for runtime in runtimes.values_mut() {
+        runtime.next_poll = 0;
+        runtime.skip_next_pass = false;
+    }
+}
+
+// Apply queued commands to the repo runtimes
+pub fn apply_control_commands(queue: &ControlQueue, runtimes: &mut RepoRuntimes) {
+    let commands: Vec<ControlCommand> = match queue.lock() {
+        Ok(mut queue) => queue.drain(..).collect(),
+        Err(_) => return,
+    };
+
+    for command in commands {
+        let (id, runtime) = match &command {
+            ControlCommand::Pull(id) | ControlCommand::Pause(id) | ControlCommand::Resume(id) => {
+                match runtimes.get_mut(id) {
+                    Some(runtime) => (id, runtime),
+                    None => {
+                        log!(LogLevel::Warn, "Control command for unknown repo {}", id);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        match &command {
+            ControlCommand::Pull(_) => {
+                log!(LogLevel::Info, "Pulling {} on request", id);
+                runtime.next_poll = 0;
+                runtime.skip_next_pass = false;
+                runtime.resume();
+            }
+            ControlCommand::Pause(_) => {
+                log!(LogLevel::Info, "Pausing {}", id);
+                runtime.paused = true;
+            }
+            ControlCommand::Resume(_) => {
+                log!(LogLevel::Info, "Resuming {}", id);
+                runtime.resume();
+            }
+        }
+    }
+}
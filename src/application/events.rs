@@ -0,0 +1,46 @@
+use artisan_middleware::timestamp::current_timestamp;
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::{get_log_level, LogLevel};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Whether repo events are written as json lines, kept in sync with the config by the main loop
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json_logs(enabled: bool) {
+    JSON_LOGS.store(enabled, Ordering::Relaxed);
+}
+
+// Verbosity of a level, higher levels are only shown at a more verbose configured level
+fn verbosity(level: &LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 0,
+        LogLevel::Warn => 1,
+        LogLevel::Info => 2,
+        LogLevel::Debug => 3,
+        LogLevel::Trace => 4,
+    }
+}
+
+// Log something that happened to a repo, as one json object per line when enabled
+pub fn repo_event(level: LogLevel, repo_id: &str, message: &str, error: Option<&str>) {
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        // println bypasses the level filter of log!, so apply it here
+        if verbosity(&level) > verbosity(&get_log_level()) {
+            return;
+        }
+        let event = serde_json::json!({
+            "timestamp": current_timestamp(),
+            "level": format!("{:?}", level),
+            "repo": repo_id,
+            "message": message,
+            "error": error,
+        });
+        println!("{}", event);
+        return;
+    }
+
+    match error {
+        Some(error) => log!(level, "{}: {}: {}", repo_id, message, error),
+        None => log!(level, "{}: {}", repo_id, message),
+    }
+}
@@ -1,119 +1,1628 @@
 use artisan_middleware::{
-    git_actions::{GitAction, GitAuth, GitServer}, users::{get_id, set_file_ownership}
+    git_actions::{GitAuth, GitServer}, users::{get_id, set_file_ownership}
 };
 use dusa_collection_utils::{
     errors::{ErrorArrayItem, Errors},
-    types::{ClonePath, PathType},
+    types::PathType,
 };
 use dusa_collection_utils::log;
 use dusa_collection_utils::log::LogLevel;
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::Path,
+    process::Output,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{process::Command, time::timeout};
 
+use crate::auth::{auth_env, is_auth_failure, refresh_tokens, resolve_token, token_for};
+use crate::config::{BranchDeletedPolicy, DivergencePolicy, HttpTuning, LocalChangesPolicy, MonitorConfig, RepoConfig};
+use crate::metrics::{record_fetch, record_pull};
 use crate::pull::pull_updates;
+use crate::remote_url::{https_remote_url, ssh_remote_url};
+use crate::runtime::CommitSummary;
 
 // Handle an existing repo: fetch, pull if upstream is ahead, set tracking, restart if needed
+// Returns true when new data was pulled
 pub async fn handle_existing_repo(
     auth: &GitAuth,
     git_project_path: &PathType,
-) -> Result<(), ErrorArrayItem> {
+    monitor: &MonitorConfig,
+    repo_config: &RepoConfig,
+) -> Result<bool, ErrorArrayItem> {
     log!(LogLevel::Trace, "Working on existing git repo {}", auth.generate_id());
     // set_safe_directory(git_project_path).await?;
-    fetch_updates(git_project_path).await?;
-
-    if is_upstream_ahead(auth, git_project_path).await? {
-        let new_data_downloaded = match pull_updates(auth, git_project_path).await {
-            Ok(d) => d,
-            Err(ea) => {
-                ea.display(false);
-                return Err(ErrorArrayItem::new(Errors::Git, format!("Errors occurred while updating, {}", auth.generate_id())))
-            },
+    let remote = repo_config.remote();
+    verify_origin(auth, git_project_path, monitor, repo_config).await?;
+    if let Some(fetch_url) = &repo_config.fetch_url {
+        ensure_origin_url(git_project_path, remote, fetch_url).await?;
+    }
+    if let Some(key) = &repo_config.ssh_key {
+        ensure_ssh_key(git_project_path, key).await?;
+    }
+    if !repo_config.sparse_paths.is_empty() {
+        ensure_sparse_paths(git_project_path, &repo_config.sparse_paths).await?;
+    }
+    if let Err(err) = fetch_updates(auth, git_project_path, remote).await {
+        // a git killed mid fetch leaves its lock behind and every later fetch fails on it
+        if !remove_stale_lock(git_project_path, &err, monitor.stale_lock_secs()) {
+            return Err(err);
+        }
+        fetch_updates(auth, git_project_path, remote).await?;
+    }
+
+    if !remote_branch_exists(auth, git_project_path, remote).await {
+        return handle_deleted_branch(auth, git_project_path, remote, monitor, repo_config.protect_local).await;
+    }
+    if let Ok(mut deleted) = deleted_branches().lock() {
+        deleted.remove(&auth.generate_id().to_string());
+    }
+
+    let upstream_ahead = is_upstream_ahead(auth, git_project_path, remote).await?;
+    log_sync_plan(auth, git_project_path, remote, upstream_ahead).await;
+
+    if upstream_ahead {
+        let skip_deploy =
+            only_skip_deploy_commits(auth, git_project_path, remote, &monitor.skip_deploy_patterns).await;
+        let ignored_only = only_ignored_paths(auth, git_project_path, remote, &repo_config.ignore_paths).await;
+
+        if repo_config.verify_signature {
+            verify_upstream_signature(auth, git_project_path, remote, monitor.signing_keyring.as_deref()).await?;
+        }
+
+        if let Some(problem) = branch_divergence(auth, git_project_path, remote).await? {
+            // protected checkouts are never reset, whatever the divergence policy says
+            if repo_config.protect_local {
+                return Err(ErrorArrayItem::new(
+                    Errors::Git,
+                    format!(
+                        "Refusing to update {}, it has {} and protect_local is set, it needs to be updated by hand",
+                        auth.generate_id(),
+                        problem
+                    ),
+                ));
+            }
+            match monitor.divergence {
+                DivergencePolicy::Warn => log!(
+                    LogLevel::Warn,
+                    "{} has {}, updating may overwrite local commits",
+                    auth.generate_id(),
+                    problem
+                ),
+                DivergencePolicy::Refuse => {
+                    return Err(ErrorArrayItem::new(
+                        Errors::Git,
+                        format!("Refusing to update {}, it has {}", auth.generate_id(), problem),
+                    ))
+                }
+            }
+        }
+
+        let stashed = if has_local_changes(git_project_path).await? {
+            match monitor.local_changes {
+                LocalChangesPolicy::Skip => {
+                    log!(
+                        LogLevel::Warn,
+                        "{} has uncommitted local changes, skipping pull until they are resolved",
+                        auth.generate_id()
+                    );
+                    return Ok(false);
+                }
+                LocalChangesPolicy::Stash => {
+                    log!(LogLevel::Warn, "{} has uncommitted local changes, stashing them", auth.generate_id());
+                    run_git_as_author(monitor, git_project_path, &["stash", "push", "-m", "ais_gitmon auto stash"])
+                        .await?;
+                    true
+                }
+            }
+        } else {
+            false
         };
 
+        let pull_result = if repo_config.protect_local {
+            fast_forward(auth, git_project_path, remote).await
+        } else {
+            pull_updates(auth, git_project_path, remote, repo_config.pull_strategy)
+                .await
+                .map_err(|ea| {
+                    ea.display(false);
+                    ErrorArrayItem::new(Errors::Git, format!("Errors occurred while updating, {}", auth.generate_id()))
+                })
+        };
+
+        if stashed {
+            if let Err(e) = run_git_as_author(monitor, git_project_path, &["stash", "pop"]).await {
+                log!(
+                    LogLevel::Error,
+                    "Couldn't restore the stashed changes of {}, they are kept in the stash: {}",
+                    auth.generate_id(),
+                    e
+                );
+            }
+        }
+
+        let new_data_downloaded = pull_result?;
+
+        if new_data_downloaded {
+            record_pull(auth);
+        }
+
+        if new_data_downloaded && repo_config.submodules {
+            update_submodules(auth, git_project_path, false).await?;
+        }
+
+        if new_data_downloaded && repo_config.lfs {
+            pull_lfs_objects(auth, git_project_path).await?;
+        }
+
+        if new_data_downloaded && skip_deploy {
+            log!(LogLevel::Info, "{} was updated with skip deploy commits only", auth.generate_id());
+            return Ok(false);
+        }
+
+        if new_data_downloaded && ignored_only {
+            log!(LogLevel::Info, "{} was updated in ignored paths only", auth.generate_id());
+            return Ok(false);
+        }
+
         if new_data_downloaded {
             // finalize_git_actions(auth, git_project_path).await?;
             log!(LogLevel::Info, "{} has been updated", auth.generate_id());
         } else {
             log!(LogLevel::Trace, "No new data pulled for. {}", auth.generate_id());
         }
+
+        return Ok(new_data_downloaded);
+    }
+
+    Ok(false)
+}
+
+// Remove the lock file named in git's "Unable to create ... File exists" error when it is old
+// enough that no running git can own it, returns true when it was removed
+fn remove_stale_lock(git_project_path: &PathType, err: &ErrorArrayItem, max_age_secs: u64) -> bool {
+    let message = err.to_string();
+    let lock = match message
+        .split_once("Unable to create '")
+        .and_then(|(_, rest)| rest.split_once('\''))
+    {
+        Some((lock, _)) => Path::new(lock).to_path_buf(),
+        None => return false,
+    };
+
+    // only ever touch lock files of this checkout
+    let is_lock = lock.extension().is_some_and(|extension| extension == "lock");
+    if !is_lock || !lock.starts_with(git_project_path.to_string()) {
+        return false;
+    }
+
+    let age = match std::fs::metadata(&lock).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified.elapsed().unwrap_or_default(),
+        Err(_) => return false,
+    };
+    if age < Duration::from_secs(max_age_secs) {
+        log!(
+            LogLevel::Debug,
+            "{} is only {}s old, leaving it to the git that holds it",
+            lock.display(),
+            age.as_secs()
+        );
+        return false;
+    }
+
+    match std::fs::remove_file(&lock) {
+        Ok(_) => {
+            log!(LogLevel::Warn, "Removed stale lock {} ({}s old), retrying", lock.display(), age.as_secs());
+            true
+        }
+        Err(e) => {
+            log!(LogLevel::Warn, "Couldn't remove stale lock {}: {}", lock.display(), e);
+            false
+        }
     }
+}
 
+// List the remote's branches with the repo's credentials, only those matching the patterns when given
+async fn ls_remote_heads(auth: &GitAuth, repo_config: &RepoConfig, patterns: &[&str]) -> Result<String, ErrorArrayItem> {
+    let mut args: Vec<String> = Vec::new();
+    if let Some(ssh_config) = ssh_config(repo_config) {
+        args.extend([String::from("-c"), ssh_config]);
+    }
+    args.extend([String::from("ls-remote"), String::from("--heads"), remote_url(auth, repo_config)]);
+    args.extend(patterns.iter().map(|pattern| pattern.to_string()));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = run_git_authenticated(auth, &PathType::Content(String::from("/")), &args).await?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// List the remote's branches with the repo's credentials, proving it can be cloned
+pub async fn check_remote(auth: &GitAuth, repo_config: &RepoConfig) -> Result<(), ErrorArrayItem> {
+    ls_remote_heads(auth, repo_config, &[]).await?;
     Ok(())
 }
 
-pub async fn handle_new_repo(
+// Check a repo without changing any checkout, logging what a real run would do
+pub async fn dry_run_repo(
     auth: &GitAuth,
-    server: &GitServer,
     git_project_path: &PathType,
+    repo_config: &RepoConfig,
 ) -> Result<(), ErrorArrayItem> {
-    // Clone the repository
-    let git_clone = GitAction::Clone {
-        repo_name: auth.clone().repo,
-        repo_owner: auth.clone().user,
-        destination: git_project_path.clone_path(),
-        repo_branch: auth.clone().branch,
-        server: server.clone(),
+    if !git_project_path.exists() {
+        // still proves the credentials can reach the repo
+        check_remote(auth, repo_config).await?;
+        log!(
+            LogLevel::Info,
+            "Dry run: would clone {} into {}",
+            auth.generate_id(),
+            git_project_path.to_string()
+        );
+        return Ok(());
+    }
+
+    let remote = repo_config.remote();
+    if repo_config.fetch_only {
+        run_git_authenticated(auth, git_project_path, &["fetch", "--dry-run", remote]).await?;
+        log!(LogLevel::Info, "Dry run: {} mirror is reachable", auth.generate_id());
+        return Ok(());
+    }
+
+    if let Some(tag_pattern) = &repo_config.tag_pattern {
+        // only tag refs move, the checkout stays where it is
+        match newer_tag(auth, git_project_path, remote, tag_pattern).await? {
+            Some(tag) => log!(LogLevel::Info, "Dry run: would check out {} in {}", tag, auth.generate_id()),
+            None => log!(LogLevel::Info, "Dry run: {} is on the latest tag", auth.generate_id()),
+        }
+        return Ok(());
+    }
+
+    fetch_updates(auth, git_project_path, remote).await?;
+    ensure_remote_branch(auth, git_project_path, remote).await?;
+    let upstream_ahead = is_upstream_ahead(auth, git_project_path, remote).await?;
+    log_sync_plan(auth, git_project_path, remote, upstream_ahead).await;
+
+    if upstream_ahead {
+        log!(LogLevel::Info, "Dry run: would pull new commits into {}", auth.generate_id());
+    } else {
+        log!(LogLevel::Info, "Dry run: {} is up to date", auth.generate_id());
+    }
+
+    Ok(())
+}
+
+// Fetch only repos are kept as bare mirrors, returns true when a new clone was made or the branch moved
+pub async fn handle_fetch_only_repo(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    repo_config: &RepoConfig,
+) -> Result<bool, ErrorArrayItem> {
+    if !git_project_path.exists() {
+        log!(LogLevel::Trace, "Mirroring {}", auth.generate_id());
+        clone_into_place(auth, git_project_path, repo_config).await?;
+        return Ok(true);
+    }
+
+    if let Some(key) = &repo_config.ssh_key {
+        ensure_ssh_key(git_project_path, key).await?;
+    }
+
+    if let Some(fetch_url) = &repo_config.fetch_url {
+        ensure_origin_url(git_project_path, repo_config.remote(), fetch_url).await?;
+    }
+
+    let branch = auth.branch.to_string();
+    let before = rev_parse(git_project_path, &branch).await.ok();
+    run_git_authenticated(auth, git_project_path, &["fetch", "--prune", repo_config.remote()]).await?;
+    let after = rev_parse(git_project_path, &branch).await?;
+
+    if before.as_deref() != Some(after.as_str()) {
+        log!(LogLevel::Info, "{} has new commits, mirror updated", auth.generate_id());
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+// Check out the highest version tag matching the pattern, returns true when the checkout moved.
+// Protected checkouts aren't forced, git refuses when local changes would be overwritten
+pub async fn handle_tag_repo(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    tag_pattern: &str,
+    protect_local: bool,
+) -> Result<bool, ErrorArrayItem> {
+    let latest_tag = match newer_tag(auth, git_project_path, remote, tag_pattern).await? {
+        Some(tag) => tag,
+        None => return Ok(false),
+    };
+
+    if protect_local {
+        run_git(git_project_path, &["checkout", latest_tag.as_str()]).await.map_err(|e| {
+            ErrorArrayItem::new(
+                Errors::Git,
+                format!(
+                    "{} can't check out {} without overwriting local changes and protect_local is set: {}",
+                    auth.generate_id(),
+                    latest_tag,
+                    e
+                ),
+            )
+        })?;
+    } else {
+        run_git(git_project_path, &["checkout", "--force", latest_tag.as_str()]).await?;
+    }
+    log!(LogLevel::Info, "{} has been updated to {}", auth.generate_id(), latest_tag);
+    Ok(true)
+}
+
+// Fetch tags and return the highest one matching the pattern, None when the checkout is already on it
+async fn newer_tag(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    tag_pattern: &str,
+) -> Result<Option<String>, ErrorArrayItem> {
+    run_git_authenticated(auth, git_project_path, &["fetch", "--tags", "--force", remote]).await?;
+
+    let tags = run_git(
+        git_project_path,
+        &["tag", "--list", tag_pattern, "--sort=-v:refname"],
+    )
+    .await?;
+    let tags = String::from_utf8_lossy(&tags.stdout);
+    let latest_tag = match tags.lines().map(str::trim).find(|tag| !tag.is_empty()) {
+        Some(tag) => tag.to_string(),
+        None => {
+            log!(LogLevel::Debug, "No tags matching {} in {}", tag_pattern, auth.generate_id());
+            return Ok(None);
+        }
     };
-    git_clone.execute().await?;
 
-    // Set ownership to the web user
-    let webuser = get_id("www-data")?;
-    set_file_ownership(&git_project_path, webuser.0, webuser.1)?;
+    let tag_commit = rev_parse(git_project_path, &format!("{}^{{commit}}", latest_tag)).await?;
+    if head_commit(git_project_path).await? == tag_commit {
+        log!(LogLevel::Trace, "{} already on {}", auth.generate_id(), latest_tag);
+        return Ok(None);
+    }
+
+    Ok(Some(latest_tag))
+}
+
+pub async fn handle_new_repo(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    worktree_base: Option<&PathType>,
+    monitor: &MonitorConfig,
+    repo_config: &RepoConfig,
+) -> Result<(), ErrorArrayItem> {
+    let remote = repo_config.remote();
+
+    if let Some(base) = worktree_base {
+        // another branch of the repo is already cloned, share its objects instead of cloning again
+        log!(LogLevel::Trace, "Adding {} as a worktree of {}", auth.generate_id(), base.to_string());
+        let branch = auth.branch.to_string();
+        let remote_branch = format!("{}/{}", remote, branch);
+        let destination = git_project_path.to_string();
+        run_git_authenticated(auth, base, &["fetch", remote, branch.as_str()]).await?;
+        run_git(
+            base,
+            &["worktree", "add", "-B", branch.as_str(), destination.as_str(), remote_branch.as_str()],
+        )
+        .await?;
+    } else {
+        clone_into_place(auth, git_project_path, repo_config).await?;
+    }
+
+    // Later fetches can go over a different protocol than the clone
+    if let Some(fetch_url) = &repo_config.fetch_url {
+        ensure_origin_url(git_project_path, remote, fetch_url).await?;
+    }
+
+    // Set ownership to the web user, a missing user shouldn't fail the whole clone
+    match get_id(monitor.owner()) {
+        Ok(webuser) => set_file_ownership(git_project_path, webuser.0, webuser.1)?,
+        Err(err) => log!(
+            LogLevel::Warn,
+            "Couldn't look up user {}, leaving ownership of {} as is: {}",
+            monitor.owner(),
+            git_project_path.to_string(),
+            err
+        ),
+    }
 
     // Set safe directory
     set_safe_directory(git_project_path).await?;
 
     // Force switch to the correct branch after cloning
-    fetch_updates(git_project_path).await?;
+    fetch_updates(auth, git_project_path, remote).await?;
+
+    if repo_config.submodules {
+        update_submodules(auth, git_project_path, true).await?;
+    }
+
+    if repo_config.lfs {
+        pull_lfs_objects(auth, git_project_path).await?;
+    }
 
     Ok(())
 }
 
-// Set the git project as a safe directory
-pub async fn set_safe_directory(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
-    log!(LogLevel::Trace, "Setting safe dir for {}", git_project_path.to_string());
-    let set_safe = GitAction::SetSafe {
-        directory: git_project_path.clone(),
+// Replace lfs pointer files with their content
+async fn pull_lfs_objects(auth: &GitAuth, git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+    log!(LogLevel::Trace, "Pulling lfs objects of {}", auth.generate_id());
+    run_git_authenticated(auth, git_project_path, &["lfs", "pull"]).await?;
+    Ok(())
+}
+
+// Bring submodules in line with the checkout, the auth header is passed down to their fetches
+async fn update_submodules(auth: &GitAuth, git_project_path: &PathType, init: bool) -> Result<(), ErrorArrayItem> {
+    log!(LogLevel::Trace, "Updating submodules of {}", auth.generate_id());
+    let args: &[&str] = if init {
+        &["submodule", "update", "--init", "--recursive"]
+    } else {
+        &["submodule", "update", "--recursive"]
     };
-    set_safe.execute().await?;
+    run_git_authenticated(auth, git_project_path, args).await?;
+    Ok(())
+}
+
+// Directories marked safe during this run, "*" once every directory is trusted
+static SAFE_DIRECTORIES: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn safe_directories() -> &'static Mutex<HashSet<String>> {
+    SAFE_DIRECTORIES.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+// Clone into a sibling directory and move it into place once complete, an interrupted clone
+// would otherwise be mistaken for an existing checkout and fail every fetch after
+async fn clone_into_place(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    repo_config: &RepoConfig,
+) -> Result<(), ErrorArrayItem> {
+    let staging = PathType::Content(format!("{}.partial", git_project_path));
+    if staging.exists() {
+        log!(LogLevel::Warn, "Removing the leftover partial clone {}", staging.to_string());
+        remove_directory(&staging)?;
+    }
+
+    let cloned = if repo_config.fetch_only {
+        clone_mirror(auth, &staging, repo_config).await
+    } else {
+        clone_repo(auth, &staging, repo_config).await
+    };
+    if let Err(err) = cloned {
+        if staging.exists() {
+            if let Err(cleanup) = remove_directory(&staging) {
+                log!(LogLevel::Warn, "Couldn't remove the partial clone {}: {}", staging.to_string(), cleanup);
+            }
+        }
+        return Err(err);
+    }
+
+    std::fs::rename(staging.to_string(), git_project_path.to_string())
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))
+}
+
+fn remove_directory(path: &PathType) -> Result<(), ErrorArrayItem> {
+    std::fs::remove_dir_all(path.to_string()).map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))
+}
+
+// Bare mirror of every ref, for fetch_only repos
+async fn clone_mirror(auth: &GitAuth, git_project_path: &PathType, repo_config: &RepoConfig) -> Result<(), ErrorArrayItem> {
+    let mut args: Vec<String> = vec![
+        String::from("clone"),
+        String::from("--mirror"),
+        String::from("--origin"),
+        repo_config.remote().to_string(),
+    ];
+    if let Some(ssh_config) = ssh_config(repo_config) {
+        args.extend([String::from("--config"), ssh_config]);
+    }
+    args.extend([remote_url(auth, repo_config), git_project_path.to_string()]);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run_git_authenticated(auth, &PathType::Content(String::from("/")), &args).await?;
+    Ok(())
+}
+
+// Arguments of the clone, the url never carries the token, run_git_authenticated sends it
+fn clone_args(auth: &GitAuth, git_project_path: &PathType, repo_config: &RepoConfig) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        String::from("clone"),
+        String::from("--origin"),
+        repo_config.remote().to_string(),
+    ];
+    // kept in the clone's config so every later fetch uses the deploy key too
+    if let Some(ssh_config) = ssh_config(repo_config) {
+        args.extend([String::from("--config"), ssh_config]);
+    }
+    // the tree is checked out once the sparse paths are in place
+    if !repo_config.sparse_paths.is_empty() {
+        args.push(String::from("--no-checkout"));
+    }
+    // recorded as the remote's partial clone filter, later fetches keep using it
+    if let Some(filter) = repo_config.clone_filter {
+        args.push(String::from(filter.flag()));
+    }
+    args.extend([
+        String::from("--branch"),
+        auth.branch.to_string(),
+        remote_url(auth, repo_config),
+        git_project_path.to_string(),
+    ]);
+    args
+}
+
+// Clone and check out the branch
+async fn clone_repo(auth: &GitAuth, git_project_path: &PathType, repo_config: &RepoConfig) -> Result<(), ErrorArrayItem> {
+    let sparse = !repo_config.sparse_paths.is_empty();
+    let args = clone_args(auth, git_project_path, repo_config);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run_git_authenticated(auth, &PathType::Content(String::from("/")), &args).await?;
+
+    if sparse {
+        ensure_sparse_paths(git_project_path, &repo_config.sparse_paths).await?;
+        let branch = auth.branch.to_string();
+        run_git(git_project_path, &["checkout", branch.as_str()]).await?;
+    }
 
     Ok(())
 }
 
-// Fetch updates from the remote repository
-pub async fn fetch_updates(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+// Set the git project as a safe directory, once per run
+pub async fn set_safe_directory(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+    let directory = git_project_path.to_string();
+    let already_safe = safe_directories()
+        .lock()
+        .map(|safe| safe.contains("*") || safe.contains(&directory))
+        .unwrap_or(false);
+    if already_safe {
+        return Ok(());
+    }
+
+    log!(LogLevel::Trace, "Setting safe dir for {}", directory);
+    let root = PathType::Content(String::from("/"));
+    let entries = run_git(&root, &["config", "--global", "--get-all", "safe.directory"])
+        .await
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .unwrap_or_default();
+
+    if !entries.lines().any(|entry| entry.trim() == directory) {
+        run_git(&root, &["config", "--global", "--add", "safe.directory", directory.as_str()]).await?;
+    }
+
+    if let Ok(mut safe) = safe_directories().lock() {
+        safe.insert(directory);
+    }
+    Ok(())
+}
+
+// Git still refused the directory, so it has to be set again
+pub fn forget_safe_directory(git_project_path: &PathType) {
+    if let Ok(mut safe) = safe_directories().lock() {
+        safe.remove(&git_project_path.to_string());
+    }
+}
+
+// Trust every directory with a single global safe.directory entry
+pub async fn trust_all_directories() -> Result<(), ErrorArrayItem> {
+    let root = PathType::Content(String::from("/"));
+    let entries = run_git(&root, &["config", "--global", "--get-all", "safe.directory"])
+        .await
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .unwrap_or_default();
+
+    if !entries.lines().any(|entry| entry.trim() == "*") {
+        run_git(&root, &["config", "--global", "--add", "safe.directory", "*"]).await?;
+    }
+
+    if let Ok(mut safe) = safe_directories().lock() {
+        safe.insert(String::from("*"));
+    }
+    Ok(())
+}
+
+// Fetch updates from the remote repository, with the repo's token when one is available
+pub async fn fetch_updates(auth: &GitAuth, git_project_path: &PathType, remote: &str) -> Result<(), ErrorArrayItem> {
     log!(LogLevel::Trace, "Fetching updates for, {}", git_project_path.to_string());
-    let fetch_update = GitAction::Fetch {
-        destination: git_project_path.clone(),
+    let mut result = fetch_remote(auth, git_project_path, remote).await;
+
+    // a rotated token gets one retry with the reloaded one
+    if let Err(err) = &result {
+        if is_auth_failure(err) && refresh_tokens().await {
+            result = fetch_remote(auth, git_project_path, remote).await;
+        }
+    }
+
+    // checkouts chowned after cloning trip git's ownership check even when marked safe before
+    if let Err(err) = &result {
+        if err.to_string().contains("dubious ownership") {
+            log!(
+                LogLevel::Warn,
+                "Git reported dubious ownership of {}, marking it safe and retrying the fetch",
+                git_project_path.to_string()
+            );
+            forget_safe_directory(git_project_path);
+            result = match set_safe_directory(git_project_path).await {
+                Ok(_) => fetch_remote(auth, git_project_path, remote).await,
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    record_fetch(auth, result.is_ok());
+    result
+}
+
+// Pruned, so branches deleted upstream disappear from the remote tracking refs too
+async fn fetch_remote(auth: &GitAuth, git_project_path: &PathType, remote: &str) -> Result<(), ErrorArrayItem> {
+    run_git_authenticated(auth, git_project_path, &["fetch", "--prune", remote]).await?;
+    Ok(())
+}
+
+// Refuse to work on a checkout whose origin isn't the configured repo, e.g. a reused directory
+async fn verify_origin(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    monitor: &MonitorConfig,
+    repo_config: &RepoConfig,
+) -> Result<(), ErrorArrayItem> {
+    let origin = run_git(git_project_path, &["remote", "get-url", repo_config.remote()]).await?;
+    let origin = normalize_remote_url(&String::from_utf8_lossy(&origin.stdout));
+
+    let mut expected: Vec<String> = vec![https_remote_url(auth), ssh_remote_url(auth)];
+    expected.extend(repo_config.clone_url.clone());
+    expected.extend(repo_config.fetch_url.clone());
+    if expected.iter().any(|url| normalize_remote_url(url) == origin) {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "{} points at {} instead of the configured repo {}",
+        git_project_path.to_string(),
+        origin,
+        auth.generate_id()
+    );
+
+    if monitor.repair_foreign_remote && repo_config.protect_local {
+        message.push_str(", left in place as protect_local is set");
+    } else if monitor.repair_foreign_remote {
+        match std::fs::remove_dir_all(git_project_path.to_string()) {
+            Ok(_) => message.push_str(", removed it so it gets cloned again"),
+            Err(e) => message.push_str(&format!(", couldn't remove it: {}", e)),
+        }
+    }
+
+    Err(ErrorArrayItem::new(Errors::Git, message))
+}
+
+// Strip credentials, trailing slashes and the .git suffix so remotes can be compared
+fn normalize_remote_url(url: &str) -> String {
+    let url = url.trim();
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (format!("{}://", scheme), rest),
+        None => (String::new(), url),
+    };
+    let rest = match rest.split_once('@') {
+        Some((_, host)) if !scheme.is_empty() => host,
+        _ => rest,
+    };
+    let rest = rest.trim_end_matches('/');
+    let rest = rest.strip_suffix(".git").unwrap_or(rest);
+
+    format!("{}{}", scheme, rest).to_lowercase()
+}
+
+// Point the remote at the given url if it isn't already
+async fn ensure_origin_url(git_project_path: &PathType, remote: &str, url: &str) -> Result<(), ErrorArrayItem> {
+    let current = run_git(git_project_path, &["remote", "get-url", remote]).await?;
+    if String::from_utf8_lossy(&current.stdout).trim() != url {
+        log!(LogLevel::Debug, "Switching {} of {} to the configured fetch url", remote, git_project_path.to_string());
+        run_git(git_project_path, &["remote", "set-url", remote, url]).await?;
+    }
+
+    Ok(())
+}
+
+// Url the repo is cloned from: the override, the ssh form when a deploy key is set, or the https one
+fn remote_url(auth: &GitAuth, repo_config: &RepoConfig) -> String {
+    match (&repo_config.clone_url, &repo_config.ssh_key) {
+        (Some(clone_url), _) => clone_url.clone(),
+        (None, Some(_)) => ssh_remote_url(auth),
+        (None, None) => https_remote_url(auth),
+    }
+}
+
+fn ssh_command(key: &str) -> String {
+    format!("ssh -i {} -o IdentitiesOnly=yes -o BatchMode=yes", key)
+}
+
+// core.sshCommand setting using the repo's deploy key, if it has one
+fn ssh_config(repo_config: &RepoConfig) -> Option<String> {
+    repo_config
+        .ssh_key
+        .as_ref()
+        .map(|key| format!("core.sshCommand={}", ssh_command(key)))
+}
+
+// Checkouts cloned before the key was configured pick it up here
+async fn ensure_ssh_key(git_project_path: &PathType, key: &str) -> Result<(), ErrorArrayItem> {
+    let command = ssh_command(key);
+    let current = run_git(git_project_path, &["config", "--get", "core.sshCommand"]).await;
+    let current = current.map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+
+    if current.ok().as_deref() != Some(command.as_str()) {
+        log!(LogLevel::Debug, "Setting the deploy key of {}", git_project_path.to_string());
+        run_git(git_project_path, &["config", "core.sshCommand", command.as_str()]).await?;
+    }
+
+    Ok(())
+}
+
+// Limit the working tree to the configured paths, git keeps them for every later pull
+async fn ensure_sparse_paths(git_project_path: &PathType, paths: &[String]) -> Result<(), ErrorArrayItem> {
+    // a checkout that isn't sparse yet fails to list, it gets converted below
+    let current: BTreeSet<String> = match run_git(git_project_path, &["sparse-checkout", "list"]).await {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect(),
+        Err(_) => BTreeSet::new(),
     };
-    fetch_update.execute().await?;
+    let wanted: BTreeSet<String> = paths.iter().map(|path| path.trim_matches('/').to_string()).collect();
+
+    if current != wanted {
+        log!(LogLevel::Debug, "Setting sparse paths of {}", git_project_path.to_string());
+        let mut args: Vec<&str> = vec!["sparse-checkout", "set"];
+        args.extend(paths.iter().map(String::as_str));
+        run_git(git_project_path, &args).await?;
+    }
 
     Ok(())
 }
 
+// Run a git command sending the repo's token along, if there is one
+pub async fn run_git_authenticated(
+    auth: &GitAuth,
+    directory: &PathType,
+    args: &[&str],
+) -> Result<Output, ErrorArrayItem> {
+    let auth_env = match resolve_token(auth) {
+        Some(token) => auth_env(&auth.server, &token),
+        None => Vec::new(),
+    };
+    run_git_with_env(directory, args, &auth_env).await
+}
+
+// Run a git command for a remote that isn't a monitored repo, e.g. the ledger, sending the given
+// token or else the token of the server the url points at
+pub async fn run_git_for_remote(
+    remote_url: &str,
+    token: Option<&str>,
+    directory: &PathType,
+    args: &[&str],
+) -> Result<Output, ErrorArrayItem> {
+    let server = server_for_url(remote_url);
+    let auth_env = match token.map(str::to_string).or_else(|| token_for(&server)) {
+        Some(token) => auth_env(&server, &token),
+        None => Vec::new(),
+    };
+    run_git_with_env(directory, args, &auth_env).await
+}
+
+// Git server hosting a url, https or scp like ssh
+fn server_for_url(url: &str) -> GitServer {
+    let rest = url.trim().split_once("://").map_or(url.trim(), |(_, rest)| rest);
+    let rest = rest.split_once('@').map_or(rest, |(_, host)| host);
+    let host = rest.split(['/', ':']).next().unwrap_or_default().to_lowercase();
+
+    match host.as_str() {
+        "github.com" => GitServer::GitHub,
+        "gitlab.com" => GitServer::GitLab,
+        _ => GitServer::Custom(host),
+    }
+}
+
+// -c settings giving commits made by the monitor the configured identity
+pub fn author_config(monitor: &MonitorConfig) -> Vec<String> {
+    let (author_name, author_email) = monitor.commit_author();
+    vec![
+        String::from("-c"),
+        format!("user.name={}", author_name),
+        String::from("-c"),
+        format!("user.email={}", author_email),
+    ]
+}
+
+// Run a git command that may create commits with the configured identity
+pub async fn run_git_as_author(
+    monitor: &MonitorConfig,
+    directory: &PathType,
+    args: &[&str],
+) -> Result<Output, ErrorArrayItem> {
+    let identity = author_config(monitor);
+    let mut identity_args: Vec<&str> = identity.iter().map(String::as_str).collect();
+    identity_args.extend_from_slice(args);
+    run_git(directory, &identity_args).await
+}
+
+// Arguments as they can be shown in logs, credentials only ever travel in the environment
+fn display_args(args: &[&str]) -> String {
+    args.join(" ")
+}
+
+// Deadline for a single git command, kept in sync with the config by the main loop
+static GIT_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(120);
+
+// Network commands get their own, longer deadline so a slow clone isn't killed like a hung rev-parse
+static FETCH_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(600);
+
+const NETWORK_COMMANDS: [&str; 7] = ["clone", "fetch", "pull", "push", "ls-remote", "submodule", "lfs"];
+
+pub fn set_git_timeout(secs: u64, fetch_secs: u64) {
+    GIT_TIMEOUT_SECS.store(secs, Ordering::Relaxed);
+    FETCH_TIMEOUT_SECS.store(fetch_secs, Ordering::Relaxed);
+}
+
+// Whether the command talks to the remote, the first argument that isn't an option or a -c value is the subcommand
+fn is_network_command(args: &[&str]) -> bool {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == "-c" {
+            args.next();
+        } else if !arg.starts_with('-') {
+            return NETWORK_COMMANDS.contains(arg);
+        }
+    }
+
+    false
+}
+
+// Slow transfer limits and retries for network commands, kept in sync with the config by the main loop
+static HTTP_TUNING: RwLock<HttpTuning> = RwLock::new(HttpTuning {
+    low_speed_limit: None,
+    low_speed_time_secs: None,
+    retries: 0,
+    user_agent: None,
+    extra_headers: Vec::new(),
+});
+
+pub fn set_http_tuning(tuning: &HttpTuning) {
+    if let Ok(mut current) = HTTP_TUNING.write() {
+        *current = tuning.clone();
+    }
+}
+
+fn http_tuning() -> HttpTuning {
+    HTTP_TUNING.read().map(|tuning| tuning.clone()).unwrap_or_default()
+}
+
+// Failures worth running the command again for, anything else fails the same way twice
+fn is_transient_failure(err: &ErrorArrayItem) -> bool {
+    let message = err.to_string();
+    ["returned error: 5", "RPC failed", "early EOF", "Connection reset", "Operation too slow"]
+        .iter()
+        .any(|transient| message.contains(transient))
+}
+
+// Git executable used for every command run here, kept in sync with the config by the main loop
+static GIT_BINARY: RwLock<String> = RwLock::new(String::new());
+
+pub fn set_git_binary(binary: &str) {
+    if let Ok(mut current) = GIT_BINARY.write() {
+        *current = binary.to_string();
+    }
+}
+
+fn git_binary() -> String {
+    match GIT_BINARY.read() {
+        Ok(binary) if !binary.is_empty() => binary.clone(),
+        _ => String::from("git"),
+    }
+}
+
+// Run a git command inside the given directory, non zero exits are returned as errors
+pub async fn run_git(directory: &PathType, args: &[&str]) -> Result<Output, ErrorArrayItem> {
+    run_git_with_env(directory, args, &[]).await
+}
+
+async fn run_git_with_env(
+    directory: &PathType,
+    args: &[&str],
+    envs: &[(&str, String)],
+) -> Result<Output, ErrorArrayItem> {
+    if !is_network_command(args) {
+        let deadline = GIT_TIMEOUT_SECS.load(Ordering::Relaxed);
+        return run_git_once(directory, &[], args, &with_extra_headers(envs, &[]), deadline).await;
+    }
+
+    let tuning = http_tuning();
+    let mut settings: Vec<String> = Vec::new();
+    if let Some(limit) = tuning.low_speed_limit {
+        settings.extend([String::from("-c"), format!("http.lowSpeedLimit={}", limit)]);
+    }
+    if let Some(time) = tuning.low_speed_time_secs {
+        settings.extend([String::from("-c"), format!("http.lowSpeedTime={}", time)]);
+    }
+    if let Some(user_agent) = &tuning.user_agent {
+        settings.extend([String::from("-c"), format!("http.userAgent={}", user_agent)]);
+    }
+    let settings: Vec<&str> = settings.iter().map(String::as_str).collect();
+    let envs = with_extra_headers(envs, &tuning.extra_headers);
+    let deadline = FETCH_TIMEOUT_SECS.load(Ordering::Relaxed);
+
+    let mut attempt = 0;
+    loop {
+        match run_git_once(directory, &settings, args, &envs, deadline).await {
+            Err(err) if attempt < tuning.retries && is_transient_failure(&err) => {
+                attempt += 1;
+                log!(
+                    LogLevel::Debug,
+                    "git {} hit a transient error, retry {} of {}: {}",
+                    display_args(args),
+                    attempt,
+                    tuning.retries,
+                    err
+                );
+                tokio::time::sleep(Duration::from_secs(2 * u64::from(attempt))).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Extra headers appended to the GIT_CONFIG_* entries of envs, e.g. the token's Authorization header.
+// extraheader is multi valued and the headers may hold secrets, so like the token they stay out of
+// the arguments anyone can read from the process listing
+fn with_extra_headers(envs: &[(&str, String)], headers: &[String]) -> Vec<(String, String)> {
+    let count: usize = envs
+        .iter()
+        .find(|(key, _)| *key == "GIT_CONFIG_COUNT")
+        .and_then(|(_, count)| count.parse().ok())
+        .unwrap_or(0);
+    let mut merged: Vec<(String, String)> = envs
+        .iter()
+        .filter(|(key, _)| *key != "GIT_CONFIG_COUNT")
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect();
+
+    for (index, header) in headers.iter().enumerate() {
+        merged.push((format!("GIT_CONFIG_KEY_{}", count + index), String::from("http.extraheader")));
+        merged.push((format!("GIT_CONFIG_VALUE_{}", count + index), header.clone()));
+    }
+    if count + headers.len() > 0 {
+        merged.push((String::from("GIT_CONFIG_COUNT"), (count + headers.len()).to_string()));
+    }
+
+    merged
+}
+
+fn git_command(directory: &PathType, settings: &[&str], args: &[&str], envs: &[(String, String)]) -> Command {
+    let mut command = Command::new(git_binary());
+    command
+        .arg("-C")
+        .arg(directory.to_string())
+        .args(settings)
+        .args(args)
+        .envs(envs.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+        // a stalled git is killed when the timeout drops its future
+        .kill_on_drop(true);
+    command
+}
+
+async fn run_git_once(
+    directory: &PathType,
+    settings: &[&str],
+    args: &[&str],
+    envs: &[(String, String)],
+    deadline: u64,
+) -> Result<Output, ErrorArrayItem> {
+    log!(LogLevel::Trace, "Running git {} in {}", display_args(args), directory.to_string());
+    let mut command = git_command(directory, settings, args, envs);
+
+    let output = match timeout(Duration::from_secs(deadline), command.output()).await {
+        Ok(output) => output.map_err(|e| {
+            ErrorArrayItem::new(
+                Errors::Git,
+                format!("Failed to run git {}: {}", display_args(args), e),
+            )
+        })?,
+        Err(_) => {
+            return Err(ErrorArrayItem::new(
+                Errors::Git,
+                format!(
+                    "git {} timed out after {}s in {}",
+                    display_args(args),
+                    deadline,
+                    directory.to_string()
+                ),
+            ))
+        }
+    };
+
+    if !output.status.success() {
+        return Err(ErrorArrayItem::new(
+            Errors::Git,
+            format!(
+                "git {} failed in {}: {}",
+                display_args(args),
+                directory.to_string(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(output)
+}
+
+// Short hash, subject, author and commit time of a revision
+pub async fn commit_summary(git_project_path: &PathType, revision: &str) -> Result<CommitSummary, ErrorArrayItem> {
+    let output = run_git(
+        git_project_path,
+        &["log", "-1", "--pretty=%h%x09%s%x09%an%x09%ct", revision],
+    )
+    .await?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut fields = output.trim().splitn(4, '\t');
+
+    Ok(CommitSummary {
+        hash: fields.next().unwrap_or_default().to_string(),
+        subject: fields.next().unwrap_or_default().to_string(),
+        author: fields.next().unwrap_or_default().to_string(),
+        time: fields.next().and_then(|time| time.parse().ok()).unwrap_or(0),
+    })
+}
+
+// Commit hash a revision points at
+pub async fn rev_parse(git_project_path: &PathType, revision: &str) -> Result<String, ErrorArrayItem> {
+    let output = run_git(git_project_path, &["rev-parse", revision]).await?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Current commit hash of the checked out branch
+pub async fn head_commit(git_project_path: &PathType) -> Result<String, ErrorArrayItem> {
+    rev_parse(git_project_path, "HEAD").await
+}
+
+// Compare the working tree with HEAD, returns a summary of any changes made out of band
+pub async fn working_tree_drift(git_project_path: &PathType) -> Result<Option<String>, ErrorArrayItem> {
+    // untracked files survive a reset, reporting them would flag the repo as drifted forever
+    let status = run_git(git_project_path, &["status", "--porcelain", "--untracked-files=no"]).await?;
+    let status = String::from_utf8_lossy(&status.stdout).trim().to_string();
+    if status.is_empty() {
+        return Ok(None);
+    }
+
+    let diff = run_git(git_project_path, &["diff", "--stat"]).await?;
+    Ok(Some(format!(
+        "{} paths changed\n{}\n{}",
+        status.lines().count(),
+        status,
+        String::from_utf8_lossy(&diff.stdout).trim()
+    )))
+}
+
+// Look for on disk corruption, the fsck report is returned as the error
+pub async fn verify_integrity(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+    log!(LogLevel::Trace, "Running fsck on {}", git_project_path.to_string());
+    run_git(git_project_path, &["fsck", "--no-progress"]).await?;
+    Ok(())
+}
+
+// True when tracked files have uncommitted changes
+pub async fn has_local_changes(git_project_path: &PathType) -> Result<bool, ErrorArrayItem> {
+    let status = run_git(git_project_path, &["status", "--porcelain", "--untracked-files=no"]).await?;
+    Ok(!String::from_utf8_lossy(&status.stdout).trim().is_empty())
+}
+
+// Update a protected checkout, erroring out instead of resetting when it can't fast forward
+async fn fast_forward(auth: &GitAuth, git_project_path: &PathType, remote: &str) -> Result<bool, ErrorArrayItem> {
+    let before = head_commit(git_project_path).await?;
+    let remote_branch = format!("{}/{}", remote, auth.branch);
+    run_git(git_project_path, &["merge", "--ff-only", remote_branch.as_str()])
+        .await
+        .map_err(|e| {
+            ErrorArrayItem::new(
+                Errors::Git,
+                format!("{} can't be fast forwarded and protect_local is set: {}", auth.generate_id(), e),
+            )
+        })?;
+
+    Ok(head_commit(git_project_path).await? != before)
+}
+
+// Throw away tracked changes so the working tree matches HEAD again
+pub async fn reset_working_tree(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+    log!(LogLevel::Trace, "Resetting working tree of {}", git_project_path.to_string());
+    run_git(git_project_path, &["reset", "--hard", "HEAD"]).await?;
+    Ok(())
+}
+
+// Trace where the checkout is, where the remote is and what is about to happen
+async fn log_sync_plan(auth: &GitAuth, git_project_path: &PathType, remote: &str, upstream_ahead: bool) {
+    let remote_branch = format!("{}/{}", remote, auth.branch);
+    let local_sha = rev_parse(git_project_path, "HEAD")
+        .await
+        .unwrap_or_else(|_| String::from("unknown"));
+    let remote_sha = rev_parse(git_project_path, &remote_branch)
+        .await
+        .unwrap_or_else(|_| String::from("unknown"));
+    let action = if upstream_ahead { "pull" } else { "none" };
+
+    log!(
+        LogLevel::Debug,
+        "{}: local {} remote {} ({}), action: {}",
+        auth.generate_id(),
+        local_sha,
+        remote_sha,
+        remote_branch,
+        action
+    );
+}
+
+// Messages of the commits the next pull will bring in
+async fn incoming_commit_messages(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+) -> Result<Vec<String>, ErrorArrayItem> {
+    let range = format!("HEAD..{}/{}", remote, auth.branch);
+    let output = run_git(git_project_path, &["log", "--format=%B%x00", range.as_str()]).await?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .map(|message| message.trim().to_string())
+        .filter(|message| !message.is_empty())
+        .collect())
+}
+
+// True when every incoming commit carries one of the skip deploy patterns
+async fn only_skip_deploy_commits(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    patterns: &[String],
+) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+
+    match incoming_commit_messages(auth, git_project_path, remote).await {
+        Ok(messages) => {
+            !messages.is_empty()
+                && messages
+                    .iter()
+                    .all(|message| patterns.iter().any(|pattern| message.contains(pattern.as_str())))
+        }
+        Err(err) => {
+            log!(LogLevel::Warn, "Couldn't read incoming commits of {}: {}", auth.generate_id(), err);
+            false
+        }
+    }
+}
+
+// True when every file changed upstream matches one of the ignore globs
+async fn only_ignored_paths(auth: &GitAuth, git_project_path: &PathType, remote: &str, patterns: &[String]) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+
+    // git does the glob matching, whatever is left after excluding the ignored paths matters
+    let range = format!("HEAD...{}/{}", remote, auth.branch);
+    let excludes: Vec<String> = patterns
+        .iter()
+        .map(|pattern| format!(":(exclude,glob){}", pattern))
+        .collect();
+    let mut args: Vec<&str> = vec!["diff", "--name-only", range.as_str(), "--", "."];
+    args.extend(excludes.iter().map(String::as_str));
+
+    match run_git(git_project_path, &args).await {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).trim().is_empty(),
+        Err(err) => {
+            log!(LogLevel::Warn, "Couldn't diff incoming changes of {}: {}", auth.generate_id(), err);
+            false
+        }
+    }
+}
+
 // Check if the upstream branch is ahead of the local branch
 async fn is_upstream_ahead(
     auth: &GitAuth,
     git_project_path: &PathType,
+    remote: &str,
 ) -> Result<bool, ErrorArrayItem> {
-    // Assemble the remote URL
-    let remote_url = auth.assemble_remote_url();
+    Ok(commits_behind(auth, git_project_path, remote).await? > 0)
+}
 
-    // The base for comparison should be the remote branch (e.g., "origin/main")
-    let base_branch = format!("{}/{}", remote_url, auth.branch);
+// Number of upstream commits the checkout doesn't have yet
+pub async fn commits_behind(auth: &GitAuth, git_project_path: &PathType, remote: &str) -> Result<usize, ErrorArrayItem> {
+    let range = format!("HEAD..{}/{}", remote, auth.branch);
+    let output = run_git(git_project_path, &["rev-list", "--count", range.as_str()]).await?;
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .map_err(|e: std::num::ParseIntError| ErrorArrayItem::new(Errors::Git, e.to_string()))
+}
+
+// Refuse updates whose upstream tip isn't signed by a trusted key
+async fn verify_upstream_signature(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    keyring: Option<&str>,
+) -> Result<(), ErrorArrayItem> {
+    let remote_branch = format!("{}/{}", remote, auth.branch);
+    let envs: Vec<(&str, String)> = keyring
+        .map(|keyring| ("GNUPGHOME", keyring.to_string()))
+        .into_iter()
+        .collect();
+
+    if let Err(err) = run_git_with_env(git_project_path, &["verify-commit", remote_branch.as_str()], &envs).await {
+        log!(
+            LogLevel::Error,
+            "Security: upstream tip of {} failed signature verification, not pulling",
+            auth.generate_id()
+        );
+        return Err(ErrorArrayItem::new(
+            Errors::Git,
+            format!("Signature verification failed for {}: {}", auth.generate_id(), err),
+        ));
+    }
+
+    Ok(())
+}
+
+// Fetches prune, so a missing remote tracking branch means it was deleted upstream
+async fn remote_branch_exists(auth: &GitAuth, git_project_path: &PathType, remote: &str) -> bool {
+    let remote_branch = format!("refs/remotes/{}/{}", remote, auth.branch);
+    run_git(git_project_path, &["rev-parse", "--verify", "--quiet", remote_branch.as_str()])
+        .await
+        .is_ok()
+}
+
+// Repos whose deleted branch was already reported, so holding them only warns once
+static DELETED_BRANCHES: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn deleted_branches() -> &'static Mutex<HashSet<String>> {
+    DELETED_BRANCHES.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+// Whether the repo's branch was found deleted upstream and hasn't come back since
+pub fn is_branch_deleted(auth: &GitAuth) -> bool {
+    deleted_branches()
+        .lock()
+        .map(|deleted| deleted.contains(&auth.generate_id().to_string()))
+        .unwrap_or(false)
+}
+
+// Ask the remote whether a deleted branch is back, for repos whose checkout went with it.
+// A restored branch is forgotten as deleted so the next pass clones it again
+pub async fn branch_restored(auth: &GitAuth, repo_config: &RepoConfig) -> Result<bool, ErrorArrayItem> {
+    let head = format!("refs/heads/{}", auth.branch);
+    let restored = !ls_remote_heads(auth, repo_config, &[head.as_str()]).await?.is_empty();
+    if restored {
+        log!(LogLevel::Info, "Branch {} of {} is back upstream", auth.branch, auth.generate_id());
+        if let Ok(mut deleted) = deleted_branches().lock() {
+            deleted.remove(&auth.generate_id().to_string());
+        }
+    }
+    Ok(restored)
+}
+
+// Apply the branch_deleted policy, returns true when the checkout moved to the fallback branch
+async fn handle_deleted_branch(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    monitor: &MonitorConfig,
+    protect_local: bool,
+) -> Result<bool, ErrorArrayItem> {
+    // switching branches or deleting the checkout would throw away the local state
+    if protect_local && monitor.branch_deleted != BranchDeletedPolicy::WarnAndHold {
+        return Err(ErrorArrayItem::new(
+            Errors::Git,
+            format!(
+                "Branch {} of {} was deleted upstream and protect_local is set, it needs to be handled by hand",
+                auth.branch,
+                auth.generate_id()
+            ),
+        ));
+    }
+
+    let first_report = deleted_branches()
+        .lock()
+        .map(|mut deleted| deleted.insert(auth.generate_id().to_string()))
+        .unwrap_or(true);
+
+    match monitor.branch_deleted {
+        BranchDeletedPolicy::WarnAndHold => {
+            if first_report {
+                log!(
+                    LogLevel::Warn,
+                    "Branch {} of {} was deleted upstream, keeping the checkout as it is",
+                    auth.branch,
+                    auth.generate_id()
+                );
+            }
+            Ok(false)
+        }
+        BranchDeletedPolicy::DeleteLocal => {
+            log!(
+                LogLevel::Warn,
+                "Branch {} of {} was deleted upstream, removing {}",
+                auth.branch,
+                auth.generate_id(),
+                git_project_path.to_string()
+            );
+            std::fs::remove_dir_all(git_project_path.to_string())
+                .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+            Ok(false)
+        }
+        BranchDeletedPolicy::FallbackBranch => {
+            let fallback = monitor.fallback_branch();
+            let remote_fallback = format!("{}/{}", remote, fallback);
+            let target = rev_parse(git_project_path, &remote_fallback).await?;
+            if head_commit(git_project_path).await? == target {
+                return Ok(false);
+            }
+
+            if first_report {
+                log!(
+                    LogLevel::Warn,
+                    "Branch {} of {} was deleted upstream, following {} instead",
+                    auth.branch,
+                    auth.generate_id(),
+                    fallback
+                );
+            }
+            run_git(git_project_path, &["checkout", "-B", fallback, remote_fallback.as_str()]).await?;
+            Ok(true)
+        }
+    }
+}
+
+// The upstream comparison treats a missing remote branch as up to date, so name it explicitly
+async fn ensure_remote_branch(auth: &GitAuth, git_project_path: &PathType, remote: &str) -> Result<(), ErrorArrayItem> {
+    if !remote_branch_exists(auth, git_project_path, remote).await {
+        return Err(ErrorArrayItem::new(
+            Errors::Git,
+            format!("Branch {} not found on the remote of {}", auth.branch, auth.generate_id()),
+        ));
+    }
 
-    // Create the GitAction::RevList to compare the local and remote branches
-    let rev_list = GitAction::RevList {
-        base: base_branch,         // The remote branch
-        target: auth.branch.to_string(), // The local branch
-        destination: git_project_path.clone_path(),
+    Ok(())
+}
+
+// Describes a detached HEAD or local commits missing upstream, None when a pull is a clean fast forward
+async fn branch_divergence(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+) -> Result<Option<String>, ErrorArrayItem> {
+    if run_git(git_project_path, &["symbolic-ref", "-q", "HEAD"]).await.is_err() {
+        return Ok(Some(String::from("a detached HEAD")));
+    }
+
+    // a plain fast-forward, nothing local would be lost
+    let remote_branch = format!("{}/{}", remote, auth.branch);
+    if is_ancestor(git_project_path, "HEAD", &remote_branch).await {
+        return Ok(None);
+    }
+
+    let range = format!("HEAD...{}", remote_branch);
+    let output = run_git(git_project_path, &["rev-list", "--left-right", "--count", range.as_str()]).await?;
+    let counts = String::from_utf8_lossy(&output.stdout).to_string();
+    let mut counts = counts.split_whitespace().map(|count| count.parse::<usize>().unwrap_or(0));
+    let local = counts.next().unwrap_or(0);
+    let upstream = counts.next().unwrap_or(0);
+
+    // HEAD was part of the previous upstream tip, so upstream dropped it rather than us adding to it
+    let previous_tip = format!("{}@{{1}}", remote_branch);
+    if is_ancestor(git_project_path, "HEAD", &previous_tip).await {
+        log!(
+            LogLevel::Warn,
+            "History of {} was rewritten upstream, force-push detected ({} commits dropped, {} new)",
+            auth.generate_id(),
+            local,
+            upstream
+        );
+        return Ok(Some(format!(
+            "history rewritten upstream by a force-push ({} dropped, {} new commits)",
+            local, upstream
+        )));
+    }
+
+    Ok(Some(format!(
+        "diverged from upstream ({} local, {} upstream commits)",
+        local, upstream
+    )))
+}
+
+// Whether the commit is reachable from the other revision, false when either can't be resolved
+async fn is_ancestor(git_project_path: &PathType, commit: &str, revision: &str) -> bool {
+    run_git(git_project_path, &["merge-base", "--is-ancestor", commit, revision])
+        .await
+        .is_ok()
+}
+
+// Host portion of the remote url, used to group repos living on the same server
+pub fn remote_host(auth: &GitAuth) -> String {
+    let remote_url = https_remote_url(auth);
+    let without_scheme = match remote_url.split_once("://") {
+        Some((_, rest)) => rest,
+        None => remote_url.as_str(),
+    };
+    let without_user = match without_scheme.split_once('@') {
+        Some((_, rest)) => rest,
+        None => without_scheme,
     };
 
-    // Execute the RevList action to determine if the remote branch is ahead
-    match rev_list.execute().await {
-        Ok(Some(output)) => {
-            let stdout_str = String::from_utf8_lossy(&output.stdout);
-            let ahead_count: usize = stdout_str.trim().parse().unwrap_or(0);
-            Ok(ahead_count > 0) // If count > 0, upstream is ahead
+    without_user
+        .split(|c| c == '/' || c == ':')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use dusa_collection_utils::stringy::Stringy;
+    use std::path::PathBuf;
+
+    fn test_auth(server: GitServer, token: Option<&str>) -> GitAuth {
+        GitAuth {
+            user: Stringy::from("example"),
+            repo: Stringy::from("assets"),
+            branch: Stringy::from("main"),
+            token: token.map(|token| token.to_string().into()),
+            server,
+        }
+    }
+
+    // Empty directory under the system temp dir, unique per test and process
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ais_gitmon_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn commit_file(repo: &Path, name: &str, content: &str) -> String {
+        std::fs::write(repo.join(name), content).unwrap();
+        git(repo, &["add", name]);
+        git(repo, &["commit", "-q", "-m", name]);
+        git(repo, &["rev-parse", "HEAD"])
+    }
+
+    // A checkout reset behind its upstream is on the right branch at an unexpected commit, the
+    // next sync fast forwards it back onto the remote branch and reports an update
+    #[tokio::test]
+    async fn local_reset_then_sync_catches_up_with_upstream() {
+        let dir = scratch_dir("local_reset");
+        let upstream = dir.join("upstream");
+        let checkout = dir.join("checkout");
+        std::fs::create_dir_all(&upstream).unwrap();
+        git(&upstream, &["init", "-q", "-b", "main"]);
+        let first = commit_file(&upstream, "deploy.txt", "one");
+        let second = commit_file(&upstream, "deploy.txt", "two");
+        git(&dir, &["clone", "-q", upstream.to_str().unwrap(), checkout.to_str().unwrap()]);
+        git(&checkout, &["reset", "-q", "--hard", first.as_str()]);
+
+        let auth = test_auth(GitServer::Custom(String::from("git.example.com")), None);
+        let repo_config = RepoConfig {
+            clone_url: Some(upstream.to_string_lossy().to_string()),
+            ..RepoConfig::default()
+        };
+        let checkout_path = PathType::Content(checkout.to_string_lossy().to_string());
+
+        let updated = handle_existing_repo(&auth, &checkout_path, &MonitorConfig::default(), &repo_config)
+            .await
+            .unwrap();
+
+        assert!(updated);
+        assert_eq!(git(&checkout, &["rev-parse", "HEAD"]), second);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Fetch, comparison and pull all go through the configured remote, nothing assumes origin
+    #[tokio::test]
+    async fn sync_follows_a_remote_not_named_origin() {
+        let dir = scratch_dir("named_remote");
+        let upstream = dir.join("upstream");
+        let checkout = dir.join("checkout");
+        std::fs::create_dir_all(&upstream).unwrap();
+        git(&upstream, &["init", "-q", "-b", "main"]);
+        commit_file(&upstream, "deploy.txt", "one");
+        let (upstream_dir, checkout_dir) = (upstream.to_str().unwrap(), checkout.to_str().unwrap());
+        git(&dir, &["clone", "-q", "--origin", "upstream", upstream_dir, checkout_dir]);
+        let second = commit_file(&upstream, "deploy.txt", "two");
+
+        let auth = test_auth(GitServer::Custom(String::from("git.example.com")), None);
+        let repo_config = RepoConfig {
+            clone_url: Some(upstream.to_string_lossy().to_string()),
+            remote: Some(String::from("upstream")),
+            ..RepoConfig::default()
+        };
+        let checkout_path = PathType::Content(checkout.to_string_lossy().to_string());
+
+        let updated = handle_existing_repo(&auth, &checkout_path, &MonitorConfig::default(), &repo_config)
+            .await
+            .unwrap();
+
+        assert!(updated);
+        assert_eq!(git(&checkout, &["rev-parse", "HEAD"]), second);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Untracked files survive a reset, only changes to tracked files count as drift
+    #[tokio::test]
+    async fn drift_ignores_untracked_files() {
+        let dir = scratch_dir("drift");
+        git(&dir, &["init", "-q", "-b", "main"]);
+        commit_file(&dir, "deploy.txt", "one");
+        let path = PathType::Content(dir.to_string_lossy().to_string());
+
+        std::fs::write(dir.join("upload.log"), "untracked").unwrap();
+        assert_eq!(working_tree_drift(&path).await.unwrap(), None);
+
+        std::fs::write(dir.join("deploy.txt"), "edited").unwrap();
+        assert!(working_tree_drift(&path).await.unwrap().is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn extra_headers_follow_the_token_in_the_environment() {
+        let envs = auth_env(&GitServer::GitHub, "s3cr3t-token");
+        let headers = vec![String::from("X-Proxy-Auth: secret"), String::from("X-Client: ais_gitmon")];
+
+        let merged = with_extra_headers(&envs, &headers);
+        let value = |key: &str| merged.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str());
+
+        assert_eq!(value("GIT_CONFIG_COUNT"), Some("3"));
+        assert!(value("GIT_CONFIG_VALUE_0").is_some_and(|value| value.starts_with("Authorization: Basic ")));
+        assert_eq!(value("GIT_CONFIG_KEY_1"), Some("http.extraheader"));
+        assert_eq!(value("GIT_CONFIG_VALUE_1"), Some("X-Proxy-Auth: secret"));
+        assert_eq!(value("GIT_CONFIG_VALUE_2"), Some("X-Client: ais_gitmon"));
+        assert_eq!(merged.iter().filter(|(name, _)| name == "GIT_CONFIG_COUNT").count(), 1);
+    }
+
+    #[test]
+    fn clone_token_only_travels_in_the_environment() {
+        let auth = test_auth(GitServer::GitHub, Some("s3cr3t-token"));
+        let token = resolve_token(&auth).unwrap();
+        let envs = with_extra_headers(&auth_env(&auth.server, &token), &[]);
+        let args = clone_args(&auth, &PathType::Content(String::from("/srv/assets")), &RepoConfig::default());
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        assert!(args.contains(&"https://github.com/example/assets.git"));
+
+        let command = git_command(&PathType::Content(String::from("/")), &[], &args, &envs);
+        let command = command.as_std();
+
+        let encoded = STANDARD.encode(format!("x-access-token:{}", token));
+        for arg in command.get_args() {
+            let arg = arg.to_string_lossy();
+            assert!(!arg.contains("s3cr3t-token") && !arg.contains(&encoded), "token leaked into {}", arg);
         }
-        _ => Ok(false),
+        assert!(command
+            .get_envs()
+            .any(|(_, value)| value.is_some_and(|value| value.to_string_lossy().contains(&encoded))));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,59 @@
+use artisan_middleware::git_actions::GitAuth;
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use dusa_collection_utils::types::PathType;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::config::RepoConfig;
+
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+// Fire the repo's update hooks in the background, failures are only logged
+pub fn run_update_hooks(repo_config: &RepoConfig, auth: &GitAuth, git_project_path: &PathType, commit: &str) {
+    let id = auth.generate_id().to_string();
+
+    if let Some(command) = &repo_config.on_update {
+        let mut child = Command::new("sh");
+        child
+            .arg("-c")
+            .arg(command)
+            .env("GITMON_REPO_ID", &id)
+            .env("GITMON_REPO_PATH", git_project_path.to_string())
+            .env("GITMON_BRANCH", auth.branch.to_string())
+            .env("GITMON_COMMIT", commit);
+
+        let id = id.clone();
+        tokio::spawn(async move {
+            match child.status().await {
+                Ok(status) if status.success() => log!(LogLevel::Debug, "Update hook of {} finished", id),
+                Ok(status) => log!(LogLevel::Warn, "Update hook of {} exited with {}", id, status),
+                Err(err) => log!(LogLevel::Warn, "Couldn't run the update hook of {}: {}", id, err),
+            }
+        });
+    }
+
+    if let Some(url) = &repo_config.on_update_url {
+        let url = url.clone();
+        let body = serde_json::json!({
+            "id": id,
+            "branch": auth.branch.to_string(),
+            "path": git_project_path.to_string(),
+            "commit": commit,
+        });
+
+        tokio::spawn(async move {
+            let response = reqwest::Client::new()
+                .post(&url)
+                .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+                .json(&body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            if let Err(err) = response {
+                log!(LogLevel::Warn, "Update webhook {} failed: {}", url, err);
+            }
+        });
+    }
+}
@@ -0,0 +1,132 @@
+use artisan_middleware::git_actions::{generate_git_project_id, GitCredentials};
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    types::PathType,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::config::RemoteCredentialsConfig;
+
+// Load the repo list from the inventory endpoint, falling back to the cached copy
+pub async fn load_remote_credentials(
+    remote: &RemoteCredentialsConfig,
+) -> Result<GitCredentials, ErrorArrayItem> {
+    let cache_file: PathType = PathType::Content(remote.cache_file.clone());
+
+    match fetch_remote_credentials(remote).await {
+        Ok(credentials) => {
+            log!(LogLevel::Trace, "Loaded repo list from {}", remote.url);
+            if let Err(err) = credentials.save(&cache_file).await {
+                log!(LogLevel::Warn, "Couldn't cache the remote repo list: {}", err);
+            }
+            Ok(credentials)
+        }
+        Err(err) => {
+            log!(
+                LogLevel::Warn,
+                "Couldn't reach {}, using cached repo list: {}",
+                remote.url,
+                err
+            );
+            GitCredentials::new(Some(&cache_file)).await
+        }
+    }
+}
+
+// Merge every credentials file in the directory, so each team can keep its own repo list
+pub async fn load_credentials_dir(directory: &str) -> Result<GitCredentials, ErrorArrayItem> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)
+        .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("{}: {}", directory, e)))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            !path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        })
+        .collect();
+    // sorted so the merged list doesn't depend on directory order
+    files.sort();
+
+    let mut merged: Option<GitCredentials> = None;
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
+
+    for file in files {
+        let credentials = load_credentials_file(&file).await?;
+
+        for git_item in &credentials.auth_items {
+            let project_id = generate_git_project_id(git_item).to_string();
+            if let Some(first) = sources.insert(project_id.clone(), file.clone()) {
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!(
+                        "{} is listed in both {} and {}",
+                        project_id,
+                        first.display(),
+                        file.display()
+                    ),
+                ));
+            }
+        }
+
+        match merged.as_mut() {
+            Some(merged) => merged.auth_items.extend(credentials.auth_items),
+            None => merged = Some(credentials),
+        }
+    }
+
+    log!(LogLevel::Trace, "Loaded {} repos from {}", sources.len(), directory);
+    merged.ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::ReadingFile,
+            format!("No credentials files found in {}", directory),
+        )
+    })
+}
+
+// Plain json and toml lists are read as is, anything else in the credentials file format
+async fn load_credentials_file(file: &Path) -> Result<GitCredentials, ErrorArrayItem> {
+    let read = || {
+        fs::read_to_string(file)
+            .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("{}: {}", file.display(), e)))
+    };
+    let invalid = |e: String| ErrorArrayItem::new(Errors::GeneralError, format!("{}: {}", file.display(), e));
+
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(&read()?).map_err(|e| invalid(e.to_string())),
+        Some("toml") => toml::from_str(&read()?).map_err(|e| invalid(e.to_string())),
+        _ => GitCredentials::new(Some(&PathType::Content(file.to_string_lossy().to_string()))).await,
+    }
+}
+
+async fn fetch_remote_credentials(
+    remote: &RemoteCredentialsConfig,
+) -> Result<GitCredentials, ErrorArrayItem> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(remote.timeout_secs))
+        .build()
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+
+    let mut request = client.get(&remote.url);
+    if let Some(token) = &remote.token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+
+    response
+        .json::<GitCredentials>()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))
+}
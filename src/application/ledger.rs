@@ -0,0 +1,130 @@
+use artisan_middleware::{git_actions::GitAuth, timestamp::current_timestamp};
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    types::PathType,
+};
+use std::{fs::OpenOptions, io::Write, path::Path, time::Duration};
+use tokio::time::sleep;
+
+use crate::config::{LedgerConfig, MonitorConfig};
+use crate::git::{author_config, head_commit, run_git, run_git_as_author, run_git_for_remote};
+use crate::pull::{MAX_RETRIES, RETRY_DELAY_SECS};
+
+// Append a deploy record for the given repo to the ledger repo and push it
+pub async fn record_deploy(
+    ledger: &LedgerConfig,
+    monitor: &MonitorConfig,
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    action: &str,
+) -> Result<(), ErrorArrayItem> {
+    let ledger_path: PathType = PathType::Content(ledger.directory.clone());
+    sync_ledger_repo(ledger, monitor, &ledger_path).await?;
+
+    let entry = serde_json::json!({
+        "id": auth.generate_id().to_string(),
+        "branch": auth.branch.to_string(),
+        "action": action,
+        "commit": head_commit(git_project_path).await?,
+        "time": current_timestamp(),
+    });
+
+    let ledger_file = Path::new(&ledger.directory).join(&ledger.file);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&ledger_file)
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+    writeln!(file, "{}", entry).map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+
+    let message = format!("Deployed {} ({})", auth.generate_id(), action);
+    let committed = match run_git(&ledger_path, &["add", ledger.file.as_str()]).await {
+        Ok(_) => run_git_as_author(monitor, &ledger_path, &["commit", "-m", message.as_str()]).await,
+        Err(e) => Err(e),
+    };
+    // an uncommitted record would make every later pull --rebase refuse to run
+    if let Err(err) = committed {
+        if let Err(reset) = run_git(&ledger_path, &["reset", "--hard", "HEAD"]).await {
+            log!(LogLevel::Error, "Couldn't reset the ledger after a failed commit: {}", reset);
+        }
+        return Err(err);
+    }
+
+    push_ledger(ledger, monitor, &ledger_path).await?;
+    log!(LogLevel::Debug, "Deploy of {} recorded in ledger", auth.generate_id());
+    Ok(())
+}
+
+// Clone the ledger repo on first use, pull it afterwards
+async fn sync_ledger_repo(
+    ledger: &LedgerConfig,
+    monitor: &MonitorConfig,
+    ledger_path: &PathType,
+) -> Result<(), ErrorArrayItem> {
+    if ledger_path.exists() {
+        pull_ledger(ledger, monitor, ledger_path).await?;
+        return Ok(());
+    }
+
+    let parent = match Path::new(&ledger.directory).parent() {
+        Some(parent) => parent.to_string_lossy().to_string(),
+        None => String::from("/"),
+    };
+    std::fs::create_dir_all(&parent).map_err(|e| ErrorArrayItem::new(Errors::GeneralError, e.to_string()))?;
+
+    run_git_for_remote(
+        &ledger.remote_url,
+        ledger.token.as_deref(),
+        &PathType::Content(parent),
+        &[
+            "clone",
+            "--branch",
+            ledger.branch.as_str(),
+            ledger.remote_url.as_str(),
+            ledger.directory.as_str(),
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+// Push the new record, rebasing on top of records other hosts pushed in the mean time
+async fn push_ledger(
+    ledger: &LedgerConfig,
+    monitor: &MonitorConfig,
+    ledger_path: &PathType,
+) -> Result<(), ErrorArrayItem> {
+    let mut retries = 0;
+
+    loop {
+        let push = ["push", "origin", ledger.branch.as_str()];
+        match run_git_for_remote(&ledger.remote_url, ledger.token.as_deref(), ledger_path, &push).await {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if retries >= MAX_RETRIES {
+                    return Err(e);
+                }
+
+                retries += 1;
+                log!(LogLevel::Warn, "Ledger push rejected, retrying after fetch: {}", e);
+                sleep(Duration::from_secs(RETRY_DELAY_SECS)).await;
+                pull_ledger(ledger, monitor, ledger_path).await?;
+            }
+        }
+    }
+}
+
+// Rebasing rewrites our unpushed records, so it needs the commit identity too
+async fn pull_ledger(
+    ledger: &LedgerConfig,
+    monitor: &MonitorConfig,
+    ledger_path: &PathType,
+) -> Result<(), ErrorArrayItem> {
+    let identity = author_config(monitor);
+    let mut args: Vec<&str> = identity.iter().map(String::as_str).collect();
+    args.extend(["pull", "--rebase", "origin", ledger.branch.as_str()]);
+    run_git_for_remote(&ledger.remote_url, ledger.token.as_deref(), ledger_path, &args).await?;
+    Ok(())
+}
@@ -1,4 +1,6 @@
 use std::{
+    collections::HashSet,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -9,13 +11,22 @@ use std::{
 
 use artisan_middleware::{
     aggregator::register_app,
-    common::{log_error, update_state},
     config::AppConfig,
-    git_actions::{generate_git_project_id, generate_git_project_path, GitCredentials},
+    git_actions::{generate_git_project_id, GitAuth, GitCredentials},
     state_persistence::{AppState, StatePersistence},
     timestamp::current_timestamp,
 };
-use config::get_config;
+use auth::{refresh_app_token, set_github_app, set_token_files};
+use capabilities::{
+    apply_git_version_policy, apply_lfs_availability, detect_git_version, detect_lfs, GitVersion,
+};
+use checks::{
+    duplicate_project_paths, entry_problems, orphaned_checkouts, validate_git_binary, validate_project_paths,
+    validate_repo_root,
+};
+use clap::{Parser, Subcommand};
+use config::{get_config, get_monitor_config, software_version, DriftPolicy, MonitorConfig};
+use control::{apply_control_commands, has_pending, pull_all, spawn_control_socket, ControlQueue};
 use dusa_collection_utils::log;
 use dusa_collection_utils::log::{set_log_level, LogLevel};
 use dusa_collection_utils::{
@@ -23,46 +34,167 @@ use dusa_collection_utils::{
     types::PathType,
     version::SoftwareVersion,
 };
-use git::{handle_existing_repo, handle_new_repo};
-use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
-use signals::sighup_watch;
+use events::{repo_event, set_json_logs};
+use git::{
+    branch_restored, commit_summary, commits_behind, dry_run_repo, handle_existing_repo, handle_fetch_only_repo,
+    handle_new_repo, handle_tag_repo, head_commit, is_branch_deleted, remote_host, reset_working_tree,
+    set_git_binary, set_git_timeout, set_http_tuning, trust_all_directories, verify_integrity,
+    working_tree_drift,
+};
+use hooks::run_update_hooks;
+use inventory::{load_credentials_dir, load_remote_credentials};
+use ledger::record_deploy;
+use metrics::{record_recovery, render_metrics, shutdown_summary};
+use persist::{log_error, recover_state, save_state_atomic};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use runtime::{backoff_path, load_backoff, save_backoff, LastError, RepoRuntimes, StateWrites};
+use selfcheck::run_self_check;
+use signals::{sighup_watch, sigterm_watch, sigusr2_watch};
+use status::{publish_status, spawn_http_server, SharedDocument};
 
+mod auth;
+mod capabilities;
+mod checks;
 mod config;
+mod control;
+mod events;
 mod git;
+mod hooks;
+mod inventory;
+mod ledger;
+mod metrics;
+mod persist;
 mod pull;
+mod remote_url;
+mod runtime;
+mod selfcheck;
 mod signals;
+mod status;
+
+#[derive(Debug, Parser)]
+#[command(about = "Keep the configured git repositories in sync with their remotes", disable_version_flag = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Report what would change without touching any checkout
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the application and library versions and exit
+    #[arg(short = 'V', long)]
+    version: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Validate the config, credentials, git and every repo's remote, then exit
+    Check,
+}
 
 #[tokio::main]
 async fn main() {
     // Initialization
+    let args: Args = Args::parse();
+    if args.version {
+        let version: SoftwareVersion = software_version();
+        println!("{} {}", env!("CARGO_PKG_NAME"), version.application);
+        println!("artisan_middleware {}", version.library);
+        return;
+    }
 
     // Loading configs
     let mut config: AppConfig = get_config();
+    let mut monitor: MonitorConfig = get_monitor_config();
+    monitor.dry_run |= args.dry_run;
+    if let Some(Command::Check) = args.command {
+        // the remote checks should behave like the daemon's git calls
+        set_git_timeout(monitor.git_timeout_secs(), monitor.fetch_timeout_secs());
+        set_http_tuning(&monitor.http);
+        set_token_files(&monitor.token_files);
+        set_github_app(monitor.github_app.clone());
+        apply_proxy(&monitor);
+        refresh_app_token(false).await;
+        std::process::exit(run_self_check(&config, &monitor).await);
+    }
+    enforce_git_binary(&monitor);
+    set_git_timeout(monitor.git_timeout_secs(), monitor.fetch_timeout_secs());
+    set_json_logs(monitor.json_logs);
+    set_http_tuning(&monitor.http);
+    set_token_files(&monitor.token_files);
+    set_github_app(monitor.github_app.clone());
+    apply_proxy(&monitor);
     let state_path: PathType = StatePersistence::get_state_path(&config);
     let mut state: AppState = load_initial_state(&config, &state_path).await;
     if let Err(err) = register_app(&state).await {
         log!(LogLevel::Error, "Failed to register app: {}", err);
     };
-    update_state(&mut state, &state_path, None).await;
+    save_state_atomic(&mut state, &state_path).await;
+
+    // Checking the installed git against the configured features
+    let git_version: Option<GitVersion> = match detect_git_version().await {
+        Ok(version) => Some(version),
+        Err(e) => {
+            log!(LogLevel::Warn, "Couldn't determine the git version: {}", e);
+            None
+        }
+    };
+    enforce_git_version(&mut monitor, git_version.as_ref());
+    enforce_repo_root(&monitor);
+    if monitor.safe_directory_wildcard {
+        if let Err(err) = trust_all_directories().await {
+            log!(LogLevel::Warn, "Couldn't add the safe.directory wildcard: {}", err);
+        }
+    }
+    let lfs_installed: bool = detect_lfs().await;
+    apply_lfs_availability(&mut monitor, lfs_installed);
 
     // loading signal handeling
     let reload: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     sighup_watch(reload.clone());
+    let shutdown: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    sigterm_watch(shutdown.clone());
+    let force_pull: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    sigusr2_watch(force_pull.clone());
 
     // Load Git credentials
-    let git_credentials: GitCredentials = match get_git_credentials(&state.config).await {
+    validate_credentials_source(&config, &monitor, &mut state);
+    let mut git_credentials: GitCredentials = match get_git_credentials(&config, &monitor).await {
         Ok(credentials) => credentials,
         Err(e) => {
             log_error(&mut state, e, &state_path).await;
             return; // Exit on failure
         }
     };
+    let dropped: Vec<GitAuth> = drop_broken_entries(&mut git_credentials, &mut state, &state_path).await;
+
+    if let Err(err) = check_duplicate_paths(&git_credentials, &monitor) {
+        log_error(&mut state, err, &state_path).await;
+        return; // Exit on failure
+    }
+
+    // a skipped entry is a config mistake, its checkout still belongs to it
+    let mut configured: GitCredentials = git_credentials.clone();
+    configured.auth_items.extend(dropped);
+    prune_orphaned_checkouts(&configured, &monitor);
+
+    let path_problems = validate_project_paths(&git_credentials.auth_items, &monitor, !monitor.dry_run);
+    if !path_problems.is_empty() {
+        let report = path_problems.join("\n");
+        log!(LogLevel::Error, "Project path problems found:\n{}", report);
+        log_error(
+            &mut state,
+            ErrorArrayItem::new(Errors::GeneralError, format!("Project path problems: {}", report)),
+            &state_path,
+        )
+        .await;
+    }
 
     // Update state to indicate initialization
     state.is_active = true;
     state.config.git = config.git.clone();
     state.data = String::from("Git monitor is initialized");
-    update_state(&mut state, &state_path, None).await;
+    save_state_atomic(&mut state, &state_path).await;
 
     if config.debug_mode {
         set_log_level(LogLevel::Debug);
@@ -72,6 +204,34 @@ async fn main() {
     };
     
     log!(LogLevel::Info, "Git monitor initialized");
+    let started_at: u64 = current_timestamp();
+    let mut state_writes: StateWrites = StateWrites::new(monitor.state_write_interval_secs());
+    let backoff_file: PathType = backoff_path(&state_path);
+    let mut runtimes: RepoRuntimes = load_backoff(&backoff_file);
+    // repos removed while the daemon was down don't need their backoff anymore
+    let known_ids: HashSet<String> = git_credentials
+        .clone()
+        .to_vec()
+        .iter()
+        .map(|git_item| generate_git_project_id(git_item).to_string())
+        .collect();
+    runtimes.retain(|project_id, _| known_ids.contains(project_id));
+
+    let status: SharedDocument = SharedDocument::default();
+    publish_status(&status, &state, &runtimes);
+    if let Some(bind_address) = &monitor.status_bind {
+        spawn_http_server(bind_address.clone(), status.clone(), "application/json");
+    }
+
+    let control: ControlQueue = ControlQueue::default();
+    if let Some(socket_path) = &monitor.control_socket {
+        spawn_control_socket(socket_path.clone(), reload.clone(), status.clone(), control.clone());
+    }
+
+    let metrics: SharedDocument = SharedDocument::default();
+    if let Some(bind_address) = &monitor.metrics_bind {
+        spawn_http_server(bind_address.clone(), metrics.clone(), "text/plain; version=0.0.4");
+    }
 
     // Main loop
     loop {
@@ -81,26 +241,114 @@ async fn main() {
 
             // Getting the new data
             config = get_config();
+            monitor = get_monitor_config();
+            monitor.dry_run |= args.dry_run;
+            enforce_git_binary(&monitor);
+            set_git_timeout(monitor.git_timeout_secs(), monitor.fetch_timeout_secs());
+            set_json_logs(monitor.json_logs);
+            set_http_tuning(&monitor.http);
+            set_token_files(&monitor.token_files);
+            set_github_app(monitor.github_app.clone());
+            apply_proxy(&monitor);
+            enforce_git_version(&mut monitor, git_version.as_ref());
+            enforce_repo_root(&monitor);
+            apply_lfs_availability(&mut monitor, lfs_installed);
             state = load_initial_state(&config, &state_path).await;
 
-            update_state(&mut state, &state_path, None).await;
+            save_state_atomic(&mut state, &state_path).await;
+
+            validate_credentials_source(&config, &monitor, &mut state);
+            match get_git_credentials(&config, &monitor).await.and_then(|credentials| {
+                check_duplicate_paths(&credentials, &monitor).map(|_| credentials)
+            }) {
+                Ok(mut credentials) => {
+                    drop_broken_entries(&mut credentials, &mut state, &state_path).await;
+                    apply_credential_changes(&git_credentials, &credentials, &mut runtimes);
+                    git_credentials = credentials;
+                }
+                Err(e) => log_error(&mut state, e, &state_path).await,
+            }
 
+            state_writes.min_interval = monitor.state_write_interval_secs();
             log!(LogLevel::Debug, "Reloaded config");
             reload.store(false, Ordering::Relaxed);
         }
 
         // Application logic
-        process_git_repositories(&git_credentials, &mut state, &state_path).await;
+        if force_pull.swap(false, Ordering::Relaxed) {
+            pull_all(&mut runtimes);
+        }
+        apply_control_commands(&control, &mut runtimes);
+        refresh_app_token(false).await;
+        let pass = process_git_repositories(
+            &git_credentials,
+            &monitor,
+            &mut runtimes,
+            &mut state_writes,
+            &mut state,
+            &state_path,
+            &shutdown,
+        );
+        // dropping the pass kills the git commands it is waiting on
+        tokio::select! {
+            _ = pass => {}
+            _ = shutdown_deadline(&shutdown, monitor.shutdown_grace_secs()) => {
+                log!(LogLevel::Warn, "Shutdown grace period ran out, aborting the running git commands");
+            }
+        }
+        state_writes.trim_error_log(&mut state, monitor.error_log_size());
+        state_writes.flush(&mut state, &state_path, current_timestamp()).await;
+        save_backoff(&backoff_file, &runtimes);
+        publish_status(&status, &state, &runtimes);
+        if monitor.metrics_bind.is_some() {
+            if let Ok(mut current) = metrics.lock() {
+                *current = render_metrics(&runtimes);
+            }
+        }
 
-        // sleep based on config
-        thread::sleep(Duration::from_secs(30));
+        // sleep based on config, waking up early to shut down, pull everything or act on a command
+        let (tick_min, tick_max) = monitor.tick_range();
+        let tick = rand::thread_rng().gen_range(tick_min..=tick_max);
+        for _ in 0..tick {
+            if shutdown.load(Ordering::Relaxed)
+                || reload.load(Ordering::Relaxed)
+                || force_pull.load(Ordering::Relaxed)
+                || has_pending(&control)
+            {
+                break;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        if shutdown.load(Ordering::Relaxed) {
+            log!(LogLevel::Info, "Shutting down: {}", shutdown_summary(&runtimes, started_at));
+            state.is_active = false;
+            state.data = String::from("Git monitor stopped");
+            state_writes.write(&mut state, &state_path, current_timestamp()).await;
+            std::process::exit(0);
+        }
     }
 }
 
+// Resolves once a shutdown was requested and the grace period has passed
+async fn shutdown_deadline(shutdown: &AtomicBool, grace_secs: u64) {
+    while !shutdown.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    log!(LogLevel::Info, "Shutdown requested, waiting up to {}s for the current repo", grace_secs);
+    tokio::time::sleep(Duration::from_secs(grace_secs)).await;
+}
+
 // Load initial state, creating a new state if necessary
 async fn load_initial_state(config: &AppConfig, state_path: &PathType) -> AppState {
-    match StatePersistence::load_state(state_path).await {
-        Ok(mut loaded_data) => {
+    let loaded = match StatePersistence::load_state(state_path).await {
+        Ok(loaded_data) => Some(loaded_data),
+        Err(_) => recover_state(state_path).await,
+    };
+
+    match loaded {
+        Some(mut loaded_data) => {
             log!(LogLevel::Debug, "Previous state data loaded");
             loaded_data.config.debug_mode = config.debug_mode;
             loaded_data.last_updated = current_timestamp();
@@ -120,29 +368,34 @@ async fn load_initial_state(config: &AppConfig, state_path: &PathType) -> AppSta
             );
             loaded_data
         }
-        Err(_) => {
+        None => {
             // this was a weird way to initalize this but it retains the config info
             log!(
                 LogLevel::Warn,
                 "No previous state file found, creating a new one"
             );
-            let state = get_initial_state(config);
-            if let Err(err) = StatePersistence::save_state(&state, state_path).await {
-                log!(
-                    LogLevel::Error,
-                    "Error occurred while saving new state: {}",
-                    err
-                );
-            }
+            let mut state = get_initial_state(config);
+            save_state_atomic(&mut state, state_path).await;
             set_log_level(state.config.log_level);
             state
         }
     }
 }
 
-// Load Git credentials from the configuration
-async fn get_git_credentials(config: &AppConfig) -> Result<GitCredentials, ErrorArrayItem> {
+// Load Git credentials from the inventory endpoint or the configured credentials file
+async fn get_git_credentials(
+    config: &AppConfig,
+    monitor: &MonitorConfig,
+) -> Result<GitCredentials, ErrorArrayItem> {
+    if let Some(remote) = &monitor.remote_credentials {
+        return load_remote_credentials(remote).await;
+    }
+
     match &config.git {
+        // a directory holds one credentials file per team
+        Some(git_config) if Path::new(&git_config.credentials_file).is_dir() => {
+            load_credentials_dir(&git_config.credentials_file).await
+        }
         Some(git_config) => {
             let git_file: PathType = PathType::Str(git_config.credentials_file.clone().into());
             GitCredentials::new(Some(&git_file)).await
@@ -154,32 +407,564 @@ async fn get_git_credentials(config: &AppConfig) -> Result<GitCredentials, Error
     }
 }
 
+// Turn off features the installed git can't handle, exiting when the policy refuses them
+fn enforce_git_version(monitor: &mut MonitorConfig, git_version: Option<&GitVersion>) {
+    if let Some(version) = git_version {
+        if let Err(err) = apply_git_version_policy(monitor, version) {
+            log!(LogLevel::Error, "{}", err);
+            std::process::exit(100);
+        }
+    }
+}
+
+// Remove or report checkouts of repos that were dropped from the credentials
+fn prune_orphaned_checkouts(git_credentials: &GitCredentials, monitor: &MonitorConfig) {
+    for orphan in orphaned_checkouts(git_credentials, monitor) {
+        if !monitor.prune || monitor.dry_run {
+            log!(LogLevel::Warn, "{} doesn't belong to any configured repo", orphan.display());
+            continue;
+        }
+
+        match std::fs::remove_dir_all(&orphan) {
+            Ok(_) => log!(LogLevel::Info, "Removed orphaned checkout {}", orphan.display()),
+            Err(err) => log!(LogLevel::Error, "Couldn't remove orphaned checkout {}: {}", orphan.display(), err),
+        }
+    }
+}
+
+// Git and the http clients pick the proxy up from the environment
+fn apply_proxy(monitor: &MonitorConfig) {
+    if let Some(proxy) = &monitor.http_proxy {
+        for name in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+            std::env::set_var(name, proxy);
+        }
+    }
+
+    if let Some(no_proxy) = &monitor.no_proxy {
+        for name in ["no_proxy", "NO_PROXY"] {
+            std::env::set_var(name, no_proxy);
+        }
+    }
+}
+
+// Clones can't land in a repo root that is missing or read only, stop instead of failing every repo
+fn enforce_repo_root(monitor: &MonitorConfig) {
+    if let Some(root) = &monitor.repo_root {
+        if let Err(err) = validate_repo_root(root) {
+            log!(LogLevel::Error, "{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Every git command depends on the binary, so a missing one is fatal
+fn enforce_git_binary(monitor: &MonitorConfig) {
+    if let Err(err) = validate_git_binary(monitor.git_binary()) {
+        log!(LogLevel::Error, "{}", err);
+        std::process::exit(1);
+    }
+    set_git_binary(monitor.git_binary());
+}
+
+// Log repos added or removed by a reload and forget the runtime data of removed ones
+fn apply_credential_changes(
+    current: &GitCredentials,
+    reloaded: &GitCredentials,
+    runtimes: &mut RepoRuntimes,
+) {
+    let project_ids = |credentials: &GitCredentials| -> HashSet<String> {
+        credentials
+            .clone()
+            .to_vec()
+            .iter()
+            .map(|git_item| generate_git_project_id(git_item).to_string())
+            .collect()
+    };
+    let current_ids = project_ids(current);
+    let reloaded_ids = project_ids(reloaded);
+
+    for added in reloaded_ids.difference(&current_ids) {
+        log!(LogLevel::Info, "Now monitoring {}", added);
+    }
+    for removed in current_ids.difference(&reloaded_ids) {
+        log!(LogLevel::Info, "No longer monitoring {}", removed);
+    }
+
+    runtimes.retain(|project_id, _| reloaded_ids.contains(project_id));
+}
+
+// Skip entries that can't work, one bad token shouldn't stop the other repos from being monitored.
+// Returns the skipped entries
+async fn drop_broken_entries(
+    git_credentials: &mut GitCredentials,
+    state: &mut AppState,
+    state_path: &PathType,
+) -> Vec<GitAuth> {
+    let mut usable: Vec<GitAuth> = Vec::new();
+    let mut dropped: Vec<GitAuth> = Vec::new();
+
+    for git_item in git_credentials.auth_items.drain(..) {
+        let problems = entry_problems(&git_item);
+        if problems.is_empty() {
+            usable.push(git_item);
+            continue;
+        }
+
+        let message = format!(
+            "Skipping {}-{}@{}: {}",
+            git_item.user,
+            git_item.repo,
+            git_item.branch,
+            problems.join(", ")
+        );
+        log!(LogLevel::Error, "{}", message);
+        log_error(state, ErrorArrayItem::new(Errors::GeneralError, message), state_path).await;
+        dropped.push(git_item);
+    }
+
+    git_credentials.auth_items = usable;
+    dropped
+}
+
+// Refuse credentials where several entries would share one checkout
+fn check_duplicate_paths(git_credentials: &GitCredentials, monitor: &MonitorConfig) -> Result<(), ErrorArrayItem> {
+    let conflicts = duplicate_project_paths(&git_credentials.auth_items, monitor);
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    let report = conflicts.join("\n");
+    log!(LogLevel::Error, "Conflicting project paths:\n{}", report);
+    Err(ErrorArrayItem::new(
+        Errors::GeneralError,
+        format!("Conflicting project paths: {}", report),
+    ))
+}
+
+// Make sure the state points at the credentials the repos are actually loaded from
+fn validate_credentials_source(config: &AppConfig, monitor: &MonitorConfig, state: &mut AppState) {
+    let configured = config.git.as_ref().map(|git| git.credentials_file.clone());
+    let recorded = state.config.git.as_ref().map(|git| git.credentials_file.clone());
+
+    if configured != recorded {
+        log!(
+            LogLevel::Warn,
+            "State referenced credentials file {:?} but {:?} is configured, using the configured file",
+            recorded,
+            configured
+        );
+        state.config.git = config.git.clone();
+    }
+
+    if let (Some(remote), Some(file)) = (&monitor.remote_credentials, configured) {
+        log!(
+            LogLevel::Info,
+            "Repo list is loaded from {}, credentials file {} is ignored",
+            remote.url,
+            file
+        );
+    }
+}
+
 // Process Git repositories, handling existing and new repos
 async fn process_git_repositories(
     git_credentials: &GitCredentials,
+    monitor: &MonitorConfig,
+    runtimes: &mut RepoRuntimes,
+    state_writes: &mut StateWrites,
     state: &mut AppState,
     state_path: &PathType,
+    shutdown: &AtomicBool,
 ) {
     let mut credentials_shuffled = git_credentials.clone();
     let mut rng: StdRng = StdRng::from_entropy();
     credentials_shuffled.auth_items.shuffle(&mut rng);
 
+    if monitor.host_batched_fetch {
+        // stable sort, repos on the same host stay shuffled but are fetched back to back
+        credentials_shuffled.auth_items.sort_by_key(remote_host);
+    }
+
+    // stable sort again, grouped repos go in their configured order after the ungrouped ones
+    credentials_shuffled.auth_items.sort_by_key(|git_item| {
+        let repo_config = monitor.repo_config(git_item);
+        (repo_config.group, repo_config.order)
+    });
+    let mut held_groups: HashSet<String> = HashSet::new();
+
     for git_item in credentials_shuffled.auth_items {
-        let git_project_path = generate_git_project_path(&git_item);
-        let result = if git_project_path.exists() {
-            handle_existing_repo(&git_item, &git_project_path).await
+        // the repo in progress is finished, the rest wait for the next start
+        if shutdown.load(Ordering::Relaxed) {
+            state.data = String::from("Git monitor stopping");
+            state_writes.write(state, state_path, current_timestamp()).await;
+            break;
+        }
+
+        let git_project_path = monitor.project_path(&git_item);
+        let repo_config = monitor.repo_config(&git_item);
+        let runtime = runtimes
+            .entry(generate_git_project_id(&git_item).to_string())
+            .or_default();
+        runtime.status.group = repo_config.group.clone();
+
+        if runtime.paused || runtime.quarantined {
+            // the rest of the group waits for it like for a member in backoff
+            if let Some(group) = &repo_config.group {
+                held_groups.insert(group.clone());
+            }
+            continue;
+        }
+
+        if let Some(reason) = &repo_config.unsupported {
+            if runtime.status.last_error.as_ref().map(|error| &error.message) != Some(reason) {
+                runtime.status.last_error = Some(LastError {
+                    time: current_timestamp(),
+                    message: reason.clone(),
+                });
+            }
+            continue;
+        }
+
+        if let Some(group) = repo_config.group.as_ref().filter(|group| held_groups.contains(*group)) {
+            log!(
+                LogLevel::Debug,
+                "Holding back {}, an earlier repo of group {} failed",
+                generate_git_project_id(&git_item),
+                group
+            );
+            continue;
+        }
+
+        if runtime.skip_next_pass {
+            runtime.skip_next_pass = false;
+            continue;
+        }
+
+        let scheduled = repo_config.poll_interval_secs.is_some()
+            || monitor.adaptive_schedule.is_some()
+            || runtime.failures > 0;
+        if scheduled && !runtime.is_due(current_timestamp()) {
+            // a group member waiting out its backoff still holds back the rest of the group
+            if let (Some(group), true) = (&repo_config.group, runtime.failures > 0) {
+                held_groups.insert(group.clone());
+            }
+            continue;
+        }
+
+        // the repo loop is sequential, so swapping the global level scopes it to this repo
+        if let Some(level) = repo_config.log_level {
+            set_log_level(level);
+        }
+
+        if let (Some(interval), true) = (monitor.fsck_interval_secs, git_project_path.exists()) {
+            if current_timestamp().saturating_sub(runtime.last_fsck) >= interval {
+                runtime.last_fsck = current_timestamp();
+                check_integrity(&git_item, &git_project_path, monitor, repo_config.protect_local, state, state_path).await;
+            }
+        }
+
+        if let (Some(policy), true) = (monitor.drift_check, git_project_path.exists()) {
+            if !repo_config.fetch_only {
+                // dry runs and protected checkouts never have their working tree reset
+                let policy = if monitor.dry_run || repo_config.protect_local { DriftPolicy::Report } else { policy };
+                runtime.status.drifted = check_drift(&git_item, &git_project_path, policy, state, state_path).await;
+            }
+        }
+
+        // a checkout gone since an earlier pass was removed by a repair or by hand
+        let reclone = !monitor.dry_run
+            && !repo_config.fetch_only
+            && runtime.status.last_commit.is_some()
+            && !git_project_path.exists();
+
+        // the deploy action taken, if any
+        let result: Result<Option<&str>, ErrorArrayItem> = if monitor.dry_run {
+            dry_run_repo(&git_item, &git_project_path, &repo_config)
+                .await
+                .map(|_| None)
+        } else if repo_config.fetch_only {
+            handle_fetch_only_repo(&git_item, &git_project_path, &repo_config)
+                .await
+                .map(|updated| updated.then_some("fetched"))
+        } else if let (Some(tag_pattern), true) = (&repo_config.tag_pattern, git_project_path.exists()) {
+            handle_tag_repo(
+                &git_item,
+                &git_project_path,
+                repo_config.remote(),
+                tag_pattern,
+                repo_config.protect_local,
+            )
+            .await
+            .map(|updated| updated.then_some("updated"))
+        } else if git_project_path.exists() {
+            handle_existing_repo(&git_item, &git_project_path, monitor, &repo_config)
+                .await
+                .map(|updated| updated.then_some("updated"))
+        } else if runtime.status.branch_deleted {
+            // the checkout was removed along with its deleted branch, cloning it would fail until it's back
+            branch_restored(&git_item, &repo_config).await.map(|_| None)
         } else {
-            handle_new_repo(&git_item, &git_item.server, &git_project_path).await
+            let worktree_base = monitor
+                .shared_worktrees
+                .then(|| worktree_base(git_credentials, &git_item, monitor))
+                .flatten();
+            match handle_new_repo(
+                &git_item,
+                &git_project_path,
+                worktree_base.as_ref(),
+                monitor,
+                &repo_config,
+            )
+            .await
+            {
+                Ok(_) => match &repo_config.tag_pattern {
+                    Some(tag_pattern) => handle_tag_repo(
+                        &git_item,
+                        &git_project_path,
+                        repo_config.remote(),
+                        tag_pattern,
+                        repo_config.protect_local,
+                    )
+                    .await
+                    .map(|_| Some("cloned")),
+                    None => Ok(Some("cloned")),
+                },
+                Err(err) => Err(err),
+            }
         };
 
-        if let Err(err) = result {
-            log_error(state, err, state_path).await;
+        runtime.status.branch_deleted = is_branch_deleted(&git_item);
+
+        // only completed clones count, failed attempts back off like any other failure
+        let recloned = reclone && matches!(&result, Ok(Some("cloned")));
+
+        let project_id = generate_git_project_id(&git_item).to_string();
+        if let Err(err) = &result {
+            if let Some(group) = &repo_config.group {
+                held_groups.insert(group.clone());
+            }
+            let backoff = runtime.record_failure(current_timestamp());
+            // transitions are logged once, the repeats only at debug level
+            if runtime.failures == 1 {
+                repo_event(LogLevel::Warn, &project_id, "now failing", Some(&err.to_string()));
+            }
+            repo_event(
+                LogLevel::Debug,
+                &project_id,
+                &format!("failed {} times in a row, next attempt in {}s", runtime.failures, backoff),
+                Some(&err.to_string()),
+            );
+
+            if monitor.fsck_after_failures == Some(runtime.failures) && git_project_path.exists() {
+                repo_event(LogLevel::Warn, &project_id, "checking integrity after repeated failures", None);
+                runtime.last_fsck = current_timestamp();
+                check_integrity(&git_item, &git_project_path, monitor, repo_config.protect_local, state, state_path).await;
+            }
+        } else {
+            if runtime.failures > 0 {
+                repo_event(
+                    LogLevel::Info,
+                    &project_id,
+                    &format!("recovered after {} failed attempts", runtime.failures),
+                    None,
+                );
+                record_recovery(&git_item);
+            }
+            runtime.failures = 0;
+            if let Some(interval) = repo_config.poll_interval_secs {
+                let spread = monitor.timing.poll_jitter_secs as i64;
+                let jitter = rng.gen_range(-spread..=spread);
+                runtime.schedule_in(interval.saturating_add_signed(jitter), current_timestamp());
+            } else if let (Some(schedule), Ok(action)) = (&monitor.adaptive_schedule, &result) {
+                runtime.reschedule(schedule, action.is_some(), current_timestamp());
+            }
+        }
+        runtime.skip_next_pass = matches!(&result, Ok(Some("cloned")));
+
+        // how far the checkout trails the remote after this pass, mirrors have no checkout
+        runtime.status.commits_behind = if repo_config.fetch_only || !git_project_path.exists() {
+            None
         } else {
-            state.event_counter += 1;
-            state.data = format!("Updated: {}", generate_git_project_id(&git_item));
-            update_state(state, state_path, None).await;
+            commits_behind(&git_item, &git_project_path, repo_config.remote()).await.ok()
+        };
+
+        match result {
+            Err(err) => {
+                runtime.status.last_error = Some(LastError {
+                    time: current_timestamp(),
+                    message: err.to_string(),
+                });
+                log_error(state, err, state_path).await
+            }
+            Ok(action) => {
+                runtime.status.last_success = Some(current_timestamp());
+                runtime.status.last_error = None;
+                runtime.status.last_commit = head_commit(&git_project_path).await.ok();
+                if let Some(action) = action {
+                    runtime.status.last_update = Some(current_timestamp());
+                    // mirrors have no checkout, their HEAD isn't the monitored branch
+                    let revision = if repo_config.fetch_only {
+                        git_item.branch.to_string()
+                    } else {
+                        String::from("HEAD")
+                    };
+                    runtime.status.last_change = commit_summary(&git_project_path, &revision).await.ok();
+                    match &runtime.status.last_change {
+                        Some(change) => repo_event(LogLevel::Info, &project_id, &format!("{}: {}", action, change), None),
+                        None => repo_event(LogLevel::Info, &project_id, action, None),
+                    }
+                }
+
+                if let (Some("updated"), Some(commit)) = (action, &runtime.status.last_commit) {
+                    run_update_hooks(&repo_config, &git_item, &git_project_path, commit);
+                }
+
+                if let (Some(action), Some(ledger)) = (action, &monitor.deploy_ledger) {
+                    if let Err(err) = record_deploy(ledger, monitor, &git_item, &git_project_path, action).await {
+                        log_error(state, err, state_path).await;
+                    }
+                }
+
+                state.event_counter += 1;
+                state.data = if monitor.dry_run {
+                    format!("Dry run checked: {}", generate_git_project_id(&git_item))
+                } else {
+                    match (action, &runtime.status.last_change) {
+                        (Some(_), Some(change)) => format!("Updated: {} ({})", project_id, change),
+                        _ => format!("Updated: {}", project_id),
+                    }
+                };
+                // updates are written right away, plain checks wait for the next flush
+                if action.is_some() {
+                    state_writes.write(state, state_path, current_timestamp()).await;
+                } else {
+                    state_writes.mark_dirty();
+                }
+            }
+        }
+
+        // something corrupting the checkout over and over would otherwise have it cloned again on every pass
+        if recloned
+            && runtime.record_reclone(current_timestamp(), monitor.reclone_window_secs(), monitor.reclone_limit())
+        {
+            let message = format!(
+                "Quarantined {}: its checkout was cloned again {} times within {}s, resume it once the cause is fixed",
+                project_id,
+                runtime.reclones.len(),
+                monitor.reclone_window_secs()
+            );
+            log!(LogLevel::Error, "{}", message);
+            runtime.quarantined = true;
+            runtime.status.last_error = Some(LastError {
+                time: current_timestamp(),
+                message: message.clone(),
+            });
+            log_error(state, ErrorArrayItem::new(Errors::GeneralError, message), state_path).await;
+        }
+
+        if repo_config.log_level.is_some() {
+            set_log_level(state.config.log_level);
+        }
+    }
+}
+
+// Existing full clone of another branch of the same repo, worktrees can't be nested
+fn worktree_base(git_credentials: &GitCredentials, git_item: &GitAuth, monitor: &MonitorConfig) -> Option<PathType> {
+    git_credentials
+        .clone()
+        .to_vec()
+        .into_iter()
+        .filter(|other| {
+            other.user == git_item.user
+                && other.repo == git_item.repo
+                && other.branch != git_item.branch
+                && remote_host(other) == remote_host(git_item)
+        })
+        .map(|other| monitor.project_path(&other))
+        .find(|path| Path::new(&path.to_string()).join(".git").is_dir())
+}
+
+// Report corruption found by fsck, removing the checkout so it gets cloned again when allowed
+async fn check_integrity(
+    git_item: &GitAuth,
+    git_project_path: &PathType,
+    monitor: &MonitorConfig,
+    protect_local: bool,
+    state: &mut AppState,
+    state_path: &PathType,
+) {
+    let err = match verify_integrity(git_project_path).await {
+        Ok(_) => return,
+        Err(err) => err,
+    };
+
+    log!(LogLevel::Error, "Integrity check of {} failed: {}", git_item.generate_id(), err);
+    log_error(state, err, state_path).await;
+
+    if monitor.fsck_reclone && protect_local {
+        log!(
+            LogLevel::Error,
+            "Not removing the corrupted checkout of {}, protect_local is set",
+            git_item.generate_id()
+        );
+    } else if monitor.fsck_reclone && !monitor.dry_run {
+        match std::fs::remove_dir_all(git_project_path.to_string()) {
+            Ok(_) => log!(
+                LogLevel::Error,
+                "Repair: removed corrupted checkout of {}, it will be cloned again",
+                git_item.generate_id()
+            ),
+            Err(e) => {
+                log_error(
+                    state,
+                    ErrorArrayItem::new(Errors::GeneralError, format!("Couldn't remove corrupted checkout: {}", e)),
+                    state_path,
+                )
+                .await
+            }
+        }
+    }
+}
+
+// Report working tree drift into the error log, resetting the tree when the policy asks for it
+// Returns whether the tree is left drifted, None when it couldn't be checked
+async fn check_drift(
+    git_item: &GitAuth,
+    git_project_path: &PathType,
+    policy: DriftPolicy,
+    state: &mut AppState,
+    state_path: &PathType,
+) -> Option<bool> {
+    let drift = match working_tree_drift(git_project_path).await {
+        Ok(Some(drift)) => drift,
+        Ok(None) => return Some(false),
+        Err(err) => {
+            log_error(state, err, state_path).await;
+            return None;
+        }
+    };
+
+    log!(LogLevel::Warn, "Working tree drift detected in {}: {}", git_item.generate_id(), drift);
+    log_error(
+        state,
+        ErrorArrayItem::new(
+            Errors::Git,
+            format!("Working tree drift in {}: {}", generate_git_project_id(git_item), drift),
+        ),
+        state_path,
+    )
+    .await;
+
+    if policy == DriftPolicy::Reset {
+        match reset_working_tree(git_project_path).await {
+            Ok(_) => {
+                log!(LogLevel::Info, "Reset drifted working tree of {}", git_item.generate_id());
+                return Some(false);
+            }
+            Err(err) => log_error(state, err, state_path).await,
         }
     }
+    Some(true)
 }
 
 // Create an initial state
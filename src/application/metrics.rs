@@ -0,0 +1,136 @@
+use artisan_middleware::{git_actions::{generate_git_project_id, GitAuth}, timestamp::current_timestamp};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::runtime::RepoRuntimes;
+
+// Git activity counters for a single repo
+#[derive(Debug, Clone, Default)]
+struct RepoCounters {
+    fetches: u64,
+    fetch_failures: u64,
+    pulls: u64,
+    recoveries: u64,
+}
+
+// Counters keyed by project id, bumped from the git helpers
+static COUNTERS: OnceLock<Mutex<HashMap<String, RepoCounters>>> = OnceLock::new();
+
+fn with_counters(auth: &GitAuth, update: impl FnOnce(&mut RepoCounters)) {
+    let counters = COUNTERS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Ok(mut counters) = counters.lock() {
+        update(
+            counters
+                .entry(generate_git_project_id(auth).to_string())
+                .or_default(),
+        );
+    }
+}
+
+pub fn record_fetch(auth: &GitAuth, succeeded: bool) {
+    with_counters(auth, |counters| {
+        counters.fetches += 1;
+        if !succeeded {
+            counters.fetch_failures += 1;
+        }
+    });
+}
+
+pub fn record_pull(auth: &GitAuth) {
+    with_counters(auth, |counters| counters.pulls += 1);
+}
+
+pub fn record_recovery(auth: &GitAuth) {
+    with_counters(auth, |counters| counters.recoveries += 1);
+}
+
+// Prometheus text exposition of the counters and per repo staleness
+pub fn render_metrics(runtimes: &RepoRuntimes) -> String {
+    let counters = match COUNTERS.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(counters) => counters.clone(),
+        Err(_) => HashMap::new(),
+    };
+    let mut output = String::new();
+
+    let _ = writeln!(output, "# TYPE gitmonitor_fetch_total counter");
+    for (id, counter) in &counters {
+        let _ = writeln!(output, "gitmonitor_fetch_total{{project=\"{}\"}} {}", id, counter.fetches);
+    }
+
+    let _ = writeln!(output, "# TYPE gitmonitor_fetch_failures_total counter");
+    for (id, counter) in &counters {
+        let _ = writeln!(output, "gitmonitor_fetch_failures_total{{project=\"{}\"}} {}", id, counter.fetch_failures);
+    }
+
+    let _ = writeln!(output, "# TYPE gitmonitor_pull_total counter");
+    for (id, counter) in &counters {
+        let _ = writeln!(output, "gitmonitor_pull_total{{project=\"{}\"}} {}", id, counter.pulls);
+    }
+
+    let _ = writeln!(output, "# TYPE gitmonitor_recoveries_total counter");
+    for (id, counter) in &counters {
+        let _ = writeln!(output, "gitmonitor_recoveries_total{{project=\"{}\"}} {}", id, counter.recoveries);
+    }
+
+    let _ = writeln!(output, "# TYPE gitmonitor_seconds_since_update gauge");
+    let now = current_timestamp();
+    for (id, runtime) in runtimes {
+        if let Some(last_update) = runtime.status.last_update {
+            let _ = writeln!(
+                output,
+                "gitmonitor_seconds_since_update{{project=\"{}\"}} {}",
+                id,
+                now.saturating_sub(last_update)
+            );
+        }
+    }
+
+    let _ = writeln!(output, "# TYPE gitmonitor_commits_behind gauge");
+    for (id, runtime) in runtimes {
+        if let Some(behind) = runtime.status.commits_behind {
+            let _ = writeln!(output, "gitmonitor_commits_behind{{project=\"{}\"}} {}", id, behind);
+        }
+    }
+
+    let _ = writeln!(output, "# TYPE gitmonitor_working_tree_drift gauge");
+    for (id, runtime) in runtimes {
+        if let Some(drifted) = runtime.status.drifted {
+            let _ = writeln!(output, "gitmonitor_working_tree_drift{{project=\"{}\"}} {}", id, u8::from(drifted));
+        }
+    }
+
+    output
+}
+
+// One line overview of this run, logged when the daemon shuts down
+pub fn shutdown_summary(runtimes: &RepoRuntimes, started_at: u64) -> String {
+    let counters = match COUNTERS.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(counters) => counters.clone(),
+        Err(_) => HashMap::new(),
+    };
+    let fetches: u64 = counters.values().map(|counter| counter.fetches).sum();
+    // the runtimes see every kind of update (clone, pull, tag, mirror fetch) and every failed pass
+    let updated = runtimes
+        .values()
+        .filter(|runtime| runtime.status.last_update.is_some_and(|time| time >= started_at))
+        .count();
+    let failing = runtimes
+        .values()
+        .filter(|runtime| runtime.status.last_error.is_some())
+        .count();
+    let uptime = current_timestamp().saturating_sub(started_at);
+
+    format!(
+        "{} repos monitored, {} updated, {} fetches, {} failing, up {}h {}m {}s",
+        runtimes.len(),
+        updated,
+        fetches,
+        failing,
+        uptime / 3600,
+        uptime % 3600 / 60,
+        uptime % 60
+    )
+}
@@ -0,0 +1,69 @@
+use artisan_middleware::{
+    state_persistence::{AppState, StatePersistence},
+    timestamp::current_timestamp,
+};
+use dusa_collection_utils::errors::ErrorArrayItem;
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use dusa_collection_utils::types::PathType;
+use std::{
+    fs::{self, File},
+    io::Write,
+};
+
+// Temp file beside the target, on the same filesystem so renaming it over the target is atomic
+fn temp_path(path: &PathType) -> PathType {
+    PathType::Content(format!("{}.tmp", path))
+}
+
+// Write a file through a temp file, readers see either the old or the new content
+pub fn write_atomic(path: &PathType, data: &str) -> std::io::Result<()> {
+    let temp = temp_path(path).to_string();
+    let mut file = File::create(&temp)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(temp, path.to_string())
+}
+
+// Save the state through a temp file so being killed mid write can't leave it truncated
+pub async fn save_state_atomic(state: &mut AppState, path: &PathType) {
+    state.last_updated = current_timestamp();
+    let temp = temp_path(path);
+
+    let result = match StatePersistence::save_state(state, &temp).await {
+        Ok(_) => fs::rename(temp.to_string(), path.to_string()).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    if let Err(err) = result {
+        log!(LogLevel::Error, "Couldn't save the state to {}: {}", path, err);
+    }
+}
+
+// Keep an error in the state's error log, written like every other state change
+pub async fn log_error(state: &mut AppState, error: ErrorArrayItem, path: &PathType) {
+    state.error_log.push(error);
+    save_state_atomic(state, path).await;
+}
+
+// A leftover temp file means the process died between writing and renaming it
+pub async fn recover_state(path: &PathType) -> Option<AppState> {
+    let temp = temp_path(path);
+    if !temp.exists() {
+        return None;
+    }
+
+    match StatePersistence::load_state(&temp).await {
+        Ok(state) => {
+            log!(LogLevel::Warn, "Recovered the state from the unfinished write {}", temp);
+            if let Err(err) = fs::rename(temp.to_string(), path.to_string()) {
+                log!(LogLevel::Warn, "Couldn't move {} into place: {}", temp, err);
+            }
+            Some(state)
+        }
+        Err(_) => {
+            let _ = fs::remove_file(temp.to_string());
+            None
+        }
+    }
+}
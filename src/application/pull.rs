@@ -1,4 +1,4 @@
-use artisan_middleware::git_actions::{GitAction, GitAuth};
+use artisan_middleware::git_actions::GitAuth;
 use dusa_collection_utils::log;
 use dusa_collection_utils::log::LogLevel;
 use dusa_collection_utils::{
@@ -8,24 +8,26 @@ use dusa_collection_utils::{
 use std::{process::Output, time::Duration};
 use tokio::time::sleep;
 
-use crate::git::{fetch_updates, set_safe_directory};
+use crate::auth::{is_auth_failure, refresh_tokens};
+use crate::config::PullStrategy;
+use crate::git::{fetch_updates, forget_safe_directory, run_git_authenticated, set_safe_directory};
 
-const MAX_RETRIES: u8 = 3; // Maximum number of retries
-const RETRY_DELAY_SECS: u64 = 3; // Delay between retries in seconds
+pub const MAX_RETRIES: u8 = 3; // Maximum number of retries
+pub const RETRY_DELAY_SECS: u64 = 3; // Delay between retries in seconds
 
-pub async fn pull_updates(auth: &GitAuth, git_project_path: &PathType) -> Result<bool, ErrorArray> {
+pub async fn pull_updates(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    strategy: PullStrategy,
+) -> Result<bool, ErrorArray> {
     log!(LogLevel::Trace, "Starting update for {}", auth.generate_id());
     let error_array = &mut ErrorArray::new_container();
     let mut retries = 0;
 
     loop {
-        let pull_update = GitAction::Pull {
-            target_branch: auth.branch.clone(),
-            destination: git_project_path.clone(),
-        };
-
         log!(LogLevel::Trace, "Pulling: {}", auth.generate_id());
-        match pull_update.execute().await {
+        match execute_pull(auth, git_project_path, remote, strategy).await {
             Ok(output) => {
                 let hpo = handle_pull_output(output);
                 match hpo {
@@ -48,7 +50,7 @@ pub async fn pull_updates(auth: &GitAuth, git_project_path: &PathType) -> Result
                 }
 
                 if let Some(result) =
-                    handle_pull_error(e, error_array, auth, git_project_path).await
+                    handle_pull_error(e, error_array, auth, git_project_path, remote).await
                 {
                     match result {
                         Ok(b) => return Ok(b),
@@ -71,6 +73,28 @@ pub async fn pull_updates(auth: &GitAuth, git_project_path: &PathType) -> Result
     }
 }
 
+// Always through git directly with the strategy spelled out, so the repo's token, remote, http
+// settings and timeout apply and git's own pull config never decides between merge and rebase
+async fn execute_pull(
+    auth: &GitAuth,
+    git_project_path: &PathType,
+    remote: &str,
+    strategy: PullStrategy,
+) -> Result<Option<Output>, ErrorArrayItem> {
+    let args = pull_args(auth, remote, strategy);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run_git_authenticated(auth, git_project_path, &args).await.map(Some)
+}
+
+fn pull_args(auth: &GitAuth, remote: &str, strategy: PullStrategy) -> Vec<String> {
+    vec![
+        String::from("pull"),
+        strategy.flag().to_string(),
+        remote.to_string(),
+        auth.branch.to_string(),
+    ]
+}
+
 fn handle_pull_output(output: Option<Output>) -> Result<bool, ErrorArrayItem> {
     if let Some(data) = output {
         let stdout_str = String::from_utf8_lossy(&data.stdout);
@@ -90,23 +114,58 @@ fn handle_pull_output(output: Option<Output>) -> Result<bool, ErrorArrayItem> {
 async fn handle_pull_error(
     e: ErrorArrayItem,
     ea: &mut ErrorArray,
-    _auth: &GitAuth,
+    auth: &GitAuth,
     git_project_path: &PathType,
+    remote: &str,
 ) -> Option<Result<bool, ErrorArray>> {
-    if e.err_type == Errors::GeneralError {
+    if e.to_string().contains("Not possible to fast-forward") {
+        // Retrying won't help, the branch has to be reconciled by hand
+        ea.push(ErrorArrayItem::new(
+            Errors::Git,
+            format!("{} can't be fast forwarded, local and upstream history diverged", auth.generate_id()),
+        ));
+        return Some(Err(ea.to_owned()));
+    } else if e.err_type == Errors::GeneralError {
         log!(LogLevel::Debug, "Non-critical errors occurred");
         return Some(Ok(true)); // Assume new data was pulled in case of non-critical error
     } else if e.to_string().contains("safe directory") {
         // Handle "safe directory" error by setting the safe directory and retrying the pull
+        forget_safe_directory(git_project_path);
         if let Err(e) = set_safe_directory(git_project_path).await {
             ea.push(e);  // Capture any errors that occur while setting the safe directory
         }
-        if let Err(e) = fetch_updates(git_project_path).await {
+        if let Err(e) = fetch_updates(auth, git_project_path, remote).await {
             ea.push(e); // Capture any errors during the fetch
         }
         // Recursively call pull_updates inside a Box to avoid infinite future size
         return None; // Allow the main loop to handle retry after a delay
+    } else if is_auth_failure(&e) && refresh_tokens().await {
+        // The token was rotated, retry with the reloaded one
+        return None;
     }
 
     Some(Err(ea.to_owned())) // Propagate any other errors
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use artisan_middleware::git_actions::GitServer;
+    use dusa_collection_utils::stringy::Stringy;
+
+    #[test]
+    fn pull_uses_the_configured_remote() {
+        let auth = GitAuth {
+            user: Stringy::from("example"),
+            repo: Stringy::from("assets"),
+            branch: Stringy::from("main"),
+            token: None,
+            server: GitServer::GitHub,
+        };
+
+        assert_eq!(
+            pull_args(&auth, "upstream", PullStrategy::FfOnly),
+            vec!["pull", "--ff-only", "upstream", "main"]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use artisan_middleware::git_actions::{GitAuth, GitServer};
+
+// Remote urls of a credentials entry, shared with the cli so both check the same url
+
+// Custom servers are entered by hand, so they may come with or without a scheme, with a port,
+// a group path (self-hosted GitLab) and trailing slashes
+fn custom_server_parts(base: &str) -> (String, String, String) {
+    let base = base.trim().trim_end_matches('/');
+    let (scheme, rest) = match base.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+        None => (String::from("https"), base),
+    };
+    let (authority, path) = match rest.split_once('/') {
+        Some((authority, path)) => (authority, path.trim_matches('/')),
+        None => (rest, ""),
+    };
+
+    (scheme, authority.to_lowercase(), path.to_string())
+}
+
+// Group path, user and repo joined without empty segments
+fn repo_path(prefix: &str, auth: &GitAuth) -> String {
+    let user = auth.user.to_string();
+    let repo = auth.repo.to_string();
+    let repo = repo.trim().trim_end_matches(".git");
+
+    [prefix, user.trim().trim_matches('/'), repo]
+        .iter()
+        .filter(|segment| !segment.is_empty())
+        .copied()
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+pub fn https_remote_url(auth: &GitAuth) -> String {
+    match &auth.server {
+        GitServer::GitHub => format!("https://github.com/{}.git", repo_path("", auth)),
+        GitServer::GitLab => format!("https://gitlab.com/{}.git", repo_path("", auth)),
+        GitServer::Custom(base) => {
+            let (scheme, authority, path) = custom_server_parts(base);
+            format!("{}://{}/{}.git", scheme, authority, repo_path(&path, auth))
+        }
+    }
+}
+
+pub fn ssh_remote_url(auth: &GitAuth) -> String {
+    match &auth.server {
+        GitServer::GitHub => format!("git@github.com:{}.git", repo_path("", auth)),
+        GitServer::GitLab => format!("git@gitlab.com:{}.git", repo_path("", auth)),
+        GitServer::Custom(base) => {
+            // the port belongs to the web server, ssh runs on its own
+            let (_, authority, path) = custom_server_parts(base);
+            let host = authority.split(':').next().unwrap_or_default();
+            format!("git@{}:{}.git", host, repo_path(&path, auth))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dusa_collection_utils::stringy::Stringy;
+
+    fn test_auth(server: GitServer) -> GitAuth {
+        GitAuth {
+            user: Stringy::from("example"),
+            repo: Stringy::from("assets"),
+            branch: Stringy::from("main"),
+            token: None,
+            server,
+        }
+    }
+
+    #[test]
+    fn remote_urls_of_hosted_servers() {
+        assert_eq!(
+            https_remote_url(&test_auth(GitServer::GitHub)),
+            "https://github.com/example/assets.git"
+        );
+        assert_eq!(
+            https_remote_url(&test_auth(GitServer::GitLab)),
+            "https://gitlab.com/example/assets.git"
+        );
+    }
+
+    #[test]
+    fn remote_urls_keep_nested_groups() {
+        let server = GitServer::Custom(String::from("https://git.example.com/platform/"));
+        let auth = GitAuth {
+            user: Stringy::from("web/frontend"),
+            ..test_auth(server)
+        };
+
+        assert_eq!(
+            https_remote_url(&auth),
+            "https://git.example.com/platform/web/frontend/assets.git"
+        );
+        assert_eq!(
+            ssh_remote_url(&auth),
+            "git@git.example.com:platform/web/frontend/assets.git"
+        );
+    }
+
+    #[test]
+    fn remote_urls_keep_the_port() {
+        assert_eq!(
+            custom_server_parts(" Git.Example.com:8443// "),
+            (String::from("https"), String::from("git.example.com:8443"), String::new())
+        );
+
+        let auth = test_auth(GitServer::Custom(String::from("http://git.example.com:8080")));
+        assert_eq!(https_remote_url(&auth), "http://git.example.com:8080/example/assets.git");
+        // ssh doesn't run on the web server's port
+        assert_eq!(ssh_remote_url(&auth), "git@git.example.com:example/assets.git");
+    }
+}
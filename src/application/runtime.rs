@@ -0,0 +1,307 @@
+use artisan_middleware::state_persistence::AppState;
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use dusa_collection_utils::types::PathType;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+use crate::config::AdaptiveSchedule;
+use crate::persist::{save_state_atomic, write_atomic};
+
+// Wait after the first failure, doubled for every failure after it
+const BACKOFF_BASE_SECS: u64 = 30;
+const BACKOFF_MAX_SECS: u64 = 15 * 60;
+
+// In memory bookkeeping for a single repo, only the backoff survives a restart
+#[derive(Debug, Clone, Default)]
+pub struct RepoRuntime {
+    // When the last fsck pass ran
+    pub last_fsck: u64,
+    // Earliest time the repo should be polled again
+    pub next_poll: u64,
+    // Current adaptive polling interval
+    pub interval: u64,
+    // Failed passes since the last success
+    pub failures: u32,
+    // Skipped until resumed through the control socket
+    pub paused: bool,
+    // Cloned again too often, skipped until resumed through the control socket
+    pub quarantined: bool,
+    // When the checkout was cloned again after being removed, within the reclone window
+    pub reclones: Vec<u64>,
+    // Just cloned, the clone already fetched the tip so the next pass has nothing to do
+    pub skip_next_pass: bool,
+    // What the repo looked like after its last pass
+    pub status: RepoStatus,
+}
+
+// Outcome of the latest passes over a repo, reported by the status endpoint
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepoStatus {
+    // When the repo was last fetched without errors
+    pub last_success: Option<u64>,
+    // When the repo was last cloned or updated
+    pub last_update: Option<u64>,
+    // Commit checked out after the last successful pass
+    pub last_commit: Option<String>,
+    // Error of the last pass, cleared by the next success
+    pub last_error: Option<LastError>,
+    // Commit brought in by the last update
+    pub last_change: Option<CommitSummary>,
+    // Upstream commits not checked out yet after the last pass
+    pub commits_behind: Option<usize>,
+    // Whether the last drift check found changes to tracked files, unset without a drift check
+    pub drifted: Option<bool>,
+    // The tracked branch is gone upstream, a checkout removed with it isn't cloned until it's back
+    pub branch_deleted: bool,
+    // Update group the repo belongs to
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LastError {
+    pub time: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommitSummary {
+    pub hash: String,
+    pub subject: String,
+    pub author: String,
+    pub time: u64,
+}
+
+impl fmt::Display for CommitSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} by {}", self.hash, self.subject, self.author)
+    }
+}
+
+impl RepoRuntime {
+    pub fn is_due(&self, now: u64) -> bool {
+        now >= self.next_poll
+    }
+
+    pub fn schedule_in(&mut self, secs: u64, now: u64) {
+        self.next_poll = now + secs.max(1);
+    }
+
+    // Reset to the short interval after an update, grow it while the repo stays up to date
+    pub fn reschedule(&mut self, schedule: &AdaptiveSchedule, updated: bool, now: u64) {
+        self.interval = if updated || self.interval == 0 {
+            schedule.min_secs
+        } else {
+            self.interval
+                .saturating_mul(schedule.multiplier)
+                .min(schedule.max_secs)
+        };
+        self.next_poll = now + self.interval;
+    }
+
+    // Back off exponentially while the repo keeps failing, returns the wait in seconds
+    pub fn record_failure(&mut self, now: u64) -> u64 {
+        self.failures = self.failures.saturating_add(1);
+        let backoff = BACKOFF_BASE_SECS
+            .saturating_mul(2u64.saturating_pow(self.failures - 1))
+            .min(BACKOFF_MAX_SECS);
+        self.next_poll = now + backoff;
+        backoff
+    }
+
+    // Count a completed clone of a checkout that existed before, returns true once the limit within the
+    // window is reached and the repo should be quarantined
+    pub fn record_reclone(&mut self, now: u64, window: u64, limit: u32) -> bool {
+        self.reclones.retain(|time| now.saturating_sub(*time) < window);
+        self.reclones.push(now);
+        self.reclones.len() >= limit as usize
+    }
+
+    // Lift a pause or quarantine, the reclone count starts over
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.quarantined = false;
+        self.reclones.clear();
+    }
+}
+
+// Runtime data keyed by project id
+pub type RepoRuntimes = HashMap<String, RepoRuntime>;
+
+// Coalesces routine state writes so a pass over many repos doesn't rewrite the state file for each
+#[derive(Debug, Default)]
+pub struct StateWrites {
+    // Routine writes are held back until this many seconds passed since the last one
+    pub min_interval: u64,
+    last_write: u64,
+    dirty: bool,
+}
+
+impl StateWrites {
+    pub fn new(min_interval: u64) -> Self {
+        Self {
+            min_interval,
+            ..Default::default()
+        }
+    }
+
+    // Record a routine change, written by the next flush that is due
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    // Write now, for changes that shouldn't wait
+    pub async fn write(&mut self, state: &mut AppState, state_path: &PathType, now: u64) {
+        save_state_atomic(state, state_path).await;
+        self.last_write = now;
+        self.dirty = false;
+    }
+
+    // Keep only the newest errors
+    pub fn trim_error_log(&mut self, state: &mut AppState, max_size: usize) {
+        if drop_oldest(&mut state.error_log, max_size) {
+            self.dirty = true;
+        }
+    }
+
+    pub async fn flush(&mut self, state: &mut AppState, state_path: &PathType, now: u64) {
+        if self.is_due(now) {
+            self.write(state, state_path, now).await;
+        }
+    }
+
+    // A held back change stays pending until the interval has passed
+    fn is_due(&self, now: u64) -> bool {
+        self.dirty && now.saturating_sub(self.last_write) >= self.min_interval
+    }
+}
+
+// Drain entries from the front until at most max_size are left, returns true when any were dropped
+fn drop_oldest<T>(entries: &mut Vec<T>, max_size: usize) -> bool {
+    let overflow = entries.len().saturating_sub(max_size);
+    entries.drain(..overflow);
+    overflow > 0
+}
+
+// Backoff of a failing repo as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedBackoff {
+    failures: u32,
+    next_poll: u64,
+}
+
+// Kept beside the state file rather than in it, loading the state resets its error log
+pub fn backoff_path(state_path: &PathType) -> PathType {
+    PathType::Content(format!("{}.backoff", state_path))
+}
+
+// Restore the backoff of repos that were failing before the restart
+pub fn load_backoff(path: &PathType) -> RepoRuntimes {
+    let data = match std::fs::read_to_string(path.to_string()) {
+        Ok(data) => data,
+        Err(_) => return RepoRuntimes::new(),
+    };
+
+    match serde_json::from_str::<HashMap<String, PersistedBackoff>>(&data) {
+        Ok(persisted) => persisted
+            .into_iter()
+            .map(|(id, backoff)| {
+                let runtime = RepoRuntime {
+                    failures: backoff.failures,
+                    next_poll: backoff.next_poll,
+                    ..Default::default()
+                };
+                (id, runtime)
+            })
+            .collect(),
+        Err(err) => {
+            log!(LogLevel::Warn, "Ignoring unreadable backoff file {}: {}", path, err);
+            RepoRuntimes::new()
+        }
+    }
+}
+
+pub fn save_backoff(path: &PathType, runtimes: &RepoRuntimes) {
+    let persisted: HashMap<&String, PersistedBackoff> = runtimes
+        .iter()
+        .filter(|(_, runtime)| runtime.failures > 0)
+        .map(|(id, runtime)| {
+            let backoff = PersistedBackoff {
+                failures: runtime.failures,
+                next_poll: runtime.next_poll,
+            };
+            (id, backoff)
+        })
+        .collect();
+
+    let result = serde_json::to_string(&persisted)
+        .map_err(|err| err.to_string())
+        .and_then(|data| write_atomic(path, &data).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        log!(LogLevel::Warn, "Couldn't save the backoff state to {}: {}", path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use artisan_middleware::config::AppConfig;
+    use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+    use dusa_collection_utils::version::SoftwareVersion;
+
+    fn errors(range: std::ops::Range<u32>) -> Vec<String> {
+        range.map(|i| format!("error {}", i)).collect()
+    }
+
+    #[test]
+    fn overflow_keeps_the_newest_errors() {
+        let mut error_log = errors(0..10);
+
+        assert!(drop_oldest(&mut error_log, 5));
+        assert_eq!(error_log, errors(5..10));
+        assert!(!drop_oldest(&mut error_log, 5));
+    }
+
+    #[test]
+    fn rate_limited_write_keeps_the_last_error_pending() {
+        let mut state = AppState {
+            data: String::new(),
+            last_updated: 0,
+            event_counter: 0,
+            is_active: true,
+            error_log: (0..10)
+                .map(|i| ErrorArrayItem::new(Errors::GeneralError, format!("error {}", i)))
+                .collect(),
+            config: AppConfig::default(),
+            name: String::from("ais_gitmon"),
+            version: SoftwareVersion::dummy(),
+            system_application: true,
+        };
+        let mut writes = StateWrites::new(60);
+        writes.last_write = 100;
+
+        writes.trim_error_log(&mut state, 5);
+
+        // held back by the interval, but neither dropped nor forgotten
+        assert_eq!(state.error_log.len(), 5);
+        assert!(state.error_log[0].to_string().contains("error 5"));
+        assert!(state.error_log[4].to_string().contains("error 9"));
+        assert!(!writes.is_due(130));
+        assert!(writes.is_due(160));
+    }
+
+    #[test]
+    fn reclones_stop_at_the_limit_until_resumed() {
+        let mut runtime = RepoRuntime::default();
+
+        assert!(!runtime.record_reclone(100, 3600, 2));
+        assert!(runtime.record_reclone(200, 3600, 2));
+        // both fell out of the window
+        assert!(!runtime.record_reclone(3900, 3600, 2));
+
+        runtime.quarantined = true;
+        runtime.resume();
+        assert!(!runtime.quarantined);
+        assert!(runtime.reclones.is_empty());
+    }
+}
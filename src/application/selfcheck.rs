@@ -0,0 +1,106 @@
+use artisan_middleware::{config::AppConfig, git_actions::generate_git_project_id};
+
+use crate::auth::resolve_token;
+use crate::capabilities::{apply_git_version_policy, detect_git_version};
+use crate::checks::{
+    duplicate_project_paths, entry_problems, validate_git_binary, validate_project_paths, validate_repo_root,
+};
+use crate::config::MonitorConfig;
+use crate::get_git_credentials;
+use crate::git::{check_remote, set_git_binary};
+
+// Outcome of every check, printed as it goes
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&self, check: &str) {
+        println!("ok    {}", check);
+    }
+
+    fn note(&self, check: &str) {
+        println!("note  {}", check);
+    }
+
+    fn fail(&mut self, check: &str, problem: &str) {
+        println!("FAIL  {}: {}", check, problem);
+        self.failures += 1;
+    }
+
+    fn record(&mut self, check: &str, result: Result<(), String>) -> bool {
+        match result {
+            Ok(_) => {
+                self.pass(check);
+                true
+            }
+            Err(problem) => {
+                self.fail(check, &problem);
+                false
+            }
+        }
+    }
+}
+
+// Validate the whole setup without entering the main loop, returns the exit code
+pub async fn run_self_check(config: &AppConfig, monitor: &MonitorConfig) -> i32 {
+    let mut report = Report { failures: 0 };
+    report.pass("config loaded");
+
+    if report.record("git binary", validate_git_binary(monitor.git_binary())) {
+        set_git_binary(monitor.git_binary());
+        let version = detect_git_version().await.map_err(|err| err.to_string());
+        let policy = version
+            .clone()
+            .and_then(|version| apply_git_version_policy(&mut monitor.clone(), &version));
+        if let Ok(version) = &version {
+            report.note(&format!("git {}", version));
+        }
+        report.record("git version", policy);
+    }
+
+    if let Some(root) = &monitor.repo_root {
+        report.record("repo root", validate_repo_root(root));
+    }
+
+    let git_credentials = match get_git_credentials(config, monitor).await {
+        Ok(credentials) => {
+            report.pass("credentials loaded");
+            credentials
+        }
+        Err(err) => {
+            report.fail("credentials loaded", &err.to_string());
+            return 1;
+        }
+    };
+
+    let duplicates = duplicate_project_paths(&git_credentials.auth_items, monitor);
+    report.record("project paths unique", if duplicates.is_empty() { Ok(()) } else { Err(duplicates.join("; ")) });
+    let path_problems = validate_project_paths(&git_credentials.auth_items, monitor, false);
+    report.record("project paths writable", if path_problems.is_empty() { Ok(()) } else { Err(path_problems.join("; ")) });
+
+    for git_item in git_credentials.clone().to_vec() {
+        let project_id = generate_git_project_id(&git_item).to_string();
+        let problems = entry_problems(&git_item);
+        if !problems.is_empty() {
+            report.fail(&project_id, &problems.join(", "));
+            continue;
+        }
+
+        if resolve_token(&git_item).is_none() {
+            report.note(&format!("{} has no token, only public access will work", project_id));
+        }
+
+        let repo_config = monitor.repo_config(&git_item);
+        let reachable = check_remote(&git_item, &repo_config).await.map_err(|err| err.to_string());
+        report.record(&format!("{} reachable", project_id), reachable);
+    }
+
+    if report.failures > 0 {
+        println!("{} check(s) failed", report.failures);
+        1
+    } else {
+        println!("all checks passed");
+        0
+    }
+}
@@ -1,4 +1,4 @@
-use signal_hook::{consts::signal::SIGHUP, iterator::Signals};
+use signal_hook::{consts::signal::{SIGHUP, SIGTERM, SIGUSR2}, iterator::Signals};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use std::thread;
 use dusa_collection_utils::log;
@@ -14,3 +14,23 @@ pub fn sighup_watch(reload: Arc<AtomicBool>) {
         }
     });    
 }
+
+pub fn sigterm_watch(shutdown: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut signals = Signals::new(&[SIGTERM]).expect("Failed to register signals");
+        for _ in signals.forever() {
+            shutdown.store(true, Ordering::Relaxed);
+            log!(LogLevel::Trace, "Received SIGTERM, marked for shutdown");
+        }
+    });
+}
+
+pub fn sigusr2_watch(force_pull: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut signals = Signals::new(&[SIGUSR2]).expect("Failed to register signals");
+        for _ in signals.forever() {
+            force_pull.store(true, Ordering::Relaxed);
+            log!(LogLevel::Trace, "Received SIGUSR2, marked every repo for a pull");
+        }
+    });
+}
@@ -0,0 +1,116 @@
+use artisan_middleware::state_persistence::AppState;
+use dusa_collection_utils::log;
+use dusa_collection_utils::log::LogLevel;
+use serde_json::{json, Map, Value};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::runtime::RepoRuntimes;
+
+// Latest rendered document, shared between the main loop and an http endpoint
+pub type SharedDocument = Arc<Mutex<String>>;
+
+// Refresh the document served by the status endpoint
+pub fn publish_status(status: &SharedDocument, state: &AppState, runtimes: &RepoRuntimes) {
+    let state = match serde_json::to_value(state) {
+        Ok(state) => state,
+        Err(err) => {
+            log!(LogLevel::Error, "Couldn't serialize the state: {}", err);
+            return;
+        }
+    };
+
+    let repos: Map<String, Value> = runtimes
+        .iter()
+        .map(|(id, runtime)| {
+            (
+                id.clone(),
+                json!({
+                    "status": runtime.status,
+                    "failures": runtime.failures,
+                    "paused": runtime.paused,
+                    "quarantined": runtime.quarantined,
+                }),
+            )
+        })
+        .collect();
+
+    // progress of each update group, a group is only done once none of its repos fail
+    let mut groups: Map<String, Value> = Map::new();
+    for runtime in runtimes.values() {
+        let group = match &runtime.status.group {
+            Some(group) => group,
+            None => continue,
+        };
+        let entry = groups
+            .entry(group.clone())
+            .or_insert_with(|| json!({ "repos": 0, "failing": 0, "last_update": null }));
+
+        entry["repos"] = json!(entry["repos"].as_u64().unwrap_or(0) + 1);
+        if runtime.failures > 0 {
+            entry["failing"] = json!(entry["failing"].as_u64().unwrap_or(0) + 1);
+        }
+        if runtime.status.last_update > entry["last_update"].as_u64() {
+            entry["last_update"] = json!(runtime.status.last_update);
+        }
+    }
+
+    // the newest failure of a repo that hasn't recovered yet, unlike the error log this is only
+    // the current problem
+    let last_error = runtimes
+        .iter()
+        .filter_map(|(id, runtime)| runtime.status.last_error.as_ref().map(|error| (id, error)))
+        .max_by_key(|(_, error)| error.time)
+        .map(|(id, error)| json!({ "time": error.time, "repo": id, "message": error.message }));
+
+    if let Ok(mut current) = status.lock() {
+        *current = json!({ "state": state, "repos": repos, "groups": groups, "last_error": last_error }).to_string();
+    }
+}
+
+// Serve the shared document to any request on the bind address
+pub fn spawn_http_server(bind_address: String, document: SharedDocument, content_type: &'static str) {
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(&bind_address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log!(LogLevel::Error, "Couldn't bind http endpoint to {}: {}", bind_address, err);
+                return;
+            }
+        };
+        log!(LogLevel::Info, "Http endpoint listening on {}", bind_address);
+
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log!(LogLevel::Warn, "Http endpoint accept failed on {}: {}", bind_address, err);
+                    continue;
+                }
+            };
+
+            let body = match document.lock() {
+                Ok(current) => current.clone(),
+                Err(_) => String::new(),
+            };
+
+            tokio::spawn(async move {
+                // the request itself doesn't matter, every path gets the document
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            });
+        }
+    });
+}
@@ -4,6 +4,9 @@ use artisan_middleware::{
     git_actions::{GitAuth, GitCredentials, GitServer},
     version::{aml_version, str_to_version},
 };
+use ::config::{Config, ConfigError, File};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use clap::{Args, Parser, Subcommand};
 use dusa_collection_utils::log;
 use dusa_collection_utils::{
     errors::ErrorArrayItem,
@@ -12,6 +15,71 @@ use dusa_collection_utils::{
     types::PathType,
     version::{SoftwareVersion, Version, VersionCode},
 };
+use serde::Deserialize;
+use serde_json::json;
+use std::process::Command as Process;
+
+// The daemon's url builder, so verify checks the same remote the monitor will use
+#[path = "../application/remote_url.rs"]
+#[allow(dead_code)]
+mod remote_url;
+
+// Cli behaviour, read from the [cli] table of the config files
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct CliConfig {
+    // Abort a bulk operation on the first bad entry instead of skipping it
+    fail_fast: bool,
+}
+
+// Without a subcommand the interactive menu is shown
+#[derive(Debug, Parser)]
+#[command(version, about = "Manage the git monitor credential file")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Don't check that new entries are reachable, for offline setup
+    #[arg(long, global = true)]
+    no_verify: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Append an entry to the credential file
+    Add(EntryArgs),
+    /// Remove an entry from the credential file
+    Remove(EntryKey),
+    /// List the stored entries
+    List {
+        /// Print the entries as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a new credential file holding a single entry
+    Create(EntryArgs),
+}
+
+#[derive(Debug, Args)]
+struct EntryKey {
+    #[arg(long)]
+    user: String,
+    #[arg(long)]
+    repo: String,
+    #[arg(long)]
+    branch: String,
+}
+
+#[derive(Debug, Args)]
+struct EntryArgs {
+    #[command(flatten)]
+    key: EntryKey,
+    /// github, gitlab or the url of a custom server
+    #[arg(long, default_value = "github")]
+    server: String,
+    #[arg(long)]
+    token: Option<String>,
+}
 
 pub fn get_config() -> AppConfig {
     let mut config: AppConfig = match AppConfig::new() {
@@ -47,6 +115,22 @@ pub fn get_config() -> AppConfig {
     config
 }
 
+fn get_cli_config() -> CliConfig {
+    let settings: Result<Config, ConfigError> = Config::builder()
+        .add_source(File::with_name("Config").required(false))
+        .add_source(File::with_name("Overrides").required(false))
+        .build();
+
+    match settings.and_then(|settings| settings.get::<CliConfig>("cli")) {
+        Ok(loaded_data) => loaded_data,
+        Err(ConfigError::NotFound(_)) => CliConfig::default(),
+        Err(e) => {
+            log!(LogLevel::Warn, "Couldn't load cli config, using defaults: {}", e);
+            CliConfig::default()
+        }
+    }
+}
+
 async fn get_git_credentials(config: &AppConfig) -> Result<GitCredentials, ErrorArrayItem> {
     match &config.git {
         Some(git_config) => {
@@ -60,6 +144,202 @@ async fn get_git_credentials(config: &AppConfig) -> Result<GitCredentials, Error
     }
 }
 
+async fn bootstrap_credentials() -> GitCredentials {
+    match GitCredentials::bootstrap_git_credentials().await {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            log!(LogLevel::Error, "Couldn't bootstrap git credentials: {}", err);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn credentials_path(config: &AppConfig) -> PathType {
+    match &config.git {
+        Some(data) => PathType::Content(data.credentials_file.clone()),
+        None => PathType::Str("/tmp/git_credenaitls".into()),
+    }
+}
+
+fn parse_server(server: &str) -> GitServer {
+    match server.to_lowercase().as_str() {
+        "github" => GitServer::GitHub,
+        "gitlab" => GitServer::GitLab,
+        _ => GitServer::Custom(normalize_server_url(server)),
+    }
+}
+
+// Trailing slashes would end up doubled in the repo url, ports and group paths are kept
+fn normalize_server_url(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_string()
+}
+
+fn server_name(server: &GitServer) -> String {
+    match server {
+        GitServer::GitHub => String::from("github"),
+        GitServer::GitLab => String::from("gitlab"),
+        GitServer::Custom(url) => url.clone(),
+    }
+}
+
+fn auth_from_args(args: EntryArgs) -> Result<GitAuth, String> {
+    for (field, value) in [
+        ("user", &args.key.user),
+        ("repo", &args.key.repo),
+        ("branch", &args.key.branch),
+    ] {
+        if value.trim().is_empty() {
+            return Err(format!("{} can't be empty", field));
+        }
+    }
+
+    Ok(GitAuth {
+        user: Stringy::from(args.key.user),
+        repo: Stringy::from(args.key.repo),
+        branch: Stringy::from(args.key.branch),
+        token: args.token.map(Into::into),
+        server: parse_server(&args.server),
+    })
+}
+
+async fn save_credentials(credentials: &GitCredentials, git_path: &PathType) -> i32 {
+    match credentials.save(git_path).await {
+        Ok(_) => {
+            log!(LogLevel::Info, "Git credentials saved @: {}", git_path);
+            0
+        }
+        Err(err) => {
+            log!(LogLevel::Error, "{}", err);
+            1
+        }
+    }
+}
+
+// Check the repo and branch exist on the remote, using the entry's token when it has one
+fn verify_remote(auth: &GitAuth) -> Result<(), String> {
+    let mut command = Process::new("git");
+
+    if let Some(token) = auth.token.as_ref().map(|token| token.to_string()) {
+        let user = match auth.server {
+            GitServer::GitHub => "x-access-token",
+            _ => "oauth2",
+        };
+        let credentials = STANDARD.encode(format!("{}:{}", user, token.trim()));
+        // through the environment so the token stays out of the process listing
+        command
+            .env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "http.extraheader")
+            .env("GIT_CONFIG_VALUE_0", format!("Authorization: Basic {}", credentials));
+    }
+
+    let output = command
+        .args(["ls-remote", "--exit-code", "--heads"])
+        .arg(remote_url::https_remote_url(auth))
+        .arg(auth.branch.to_string())
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .map_err(|err| format!("couldn't run git: {}", err))?;
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        // --exit-code reports a reachable repo without the requested ref as 2
+        Some(2) => Err(format!("branch {} doesn't exist", auth.branch)),
+        _ => Err(format!(
+            "repo isn't reachable: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+// Entry from subcommand flags, there is no one to ask so unreachable entries are rejected
+fn scripted_entry(args: EntryArgs, verify: bool) -> Result<GitAuth, String> {
+    let auth = auth_from_args(args)?;
+    if verify {
+        verify_remote(&auth).map_err(|err| format!("{}, use --no-verify to store it anyway", err))?;
+    }
+    Ok(auth)
+}
+
+// Non interactive handling of a single subcommand, returns the exit code
+async fn run_command(
+    command: Command,
+    config: &AppConfig,
+    mut git_credentials: GitCredentials,
+    verify: bool,
+) -> i32 {
+    let git_path = credentials_path(config);
+
+    match command {
+        Command::List { json } => {
+            if json {
+                let entries: Vec<serde_json::Value> = git_credentials
+                    .to_vec()
+                    .iter()
+                    .map(|git| {
+                        json!({
+                            "id": git.generate_id().to_string(),
+                            "user": git.user.to_string(),
+                            "repo": git.repo.to_string(),
+                            "branch": git.branch.to_string(),
+                            "server": server_name(&git.server),
+                        })
+                    })
+                    .collect();
+                println!("{}", json!(entries));
+            } else {
+                for git in git_credentials.to_vec() {
+                    println!("{}-{}@{} ({})", git.user, git.repo, git.branch, git.generate_id());
+                }
+            }
+            0
+        }
+        Command::Add(args) => match scripted_entry(args, verify) {
+            Ok(auth) => {
+                git_credentials.add_auth(auth);
+                save_credentials(&git_credentials, &git_path).await
+            }
+            Err(err) => {
+                log!(LogLevel::Error, "Invalid entry: {}", err);
+                1
+            }
+        },
+        Command::Create(args) => match scripted_entry(args, verify) {
+            Ok(auth) => {
+                let mut git_creds = bootstrap_credentials().await;
+                git_creds.add_auth(auth);
+                save_credentials(&git_creds, &git_path).await
+            }
+            Err(err) => {
+                log!(LogLevel::Error, "Invalid entry: {}", err);
+                1
+            }
+        },
+        Command::Remove(key) => {
+            let index = git_credentials.to_vec().iter().position(|git| {
+                git.user.to_string() == key.user
+                    && git.repo.to_string() == key.repo
+                    && git.branch.to_string() == key.branch
+            });
+
+            let index = match index {
+                Some(index) => index,
+                None => {
+                    log!(LogLevel::Error, "No entry for {}-{}@{}", key.user, key.repo, key.branch);
+                    return 1;
+                }
+            };
+
+            match git_credentials.delete_item(index).await {
+                Ok(credentials) => save_credentials(&credentials, &git_path).await,
+                Err(err) => {
+                    log!(LogLevel::Error, "Couldn't delete entry: {}", err);
+                    1
+                }
+            }
+        }
+    }
+}
+
 async fn prompt_server_choice() -> GitServer {
     println!("Select the Git server:");
     println!("1. GitHub");
@@ -74,7 +354,7 @@ async fn prompt_server_choice() -> GitServer {
             "2" => return GitServer::GitLab,
             "3" => {
                 let custom_url: Stringy = get_user_input("Enter the custom server URL: ");
-                return GitServer::Custom(custom_url.to_string());
+                return GitServer::Custom(normalize_server_url(&custom_url.to_string()));
             }
             _ => {
                 println!("Invalid choice. Please enter 1, 2, or 3.");
@@ -83,10 +363,89 @@ async fn prompt_server_choice() -> GitServer {
     }
 }
 
+// Keep asking until the user enters a valid number
+fn prompt_count(prompt: &str) -> usize {
+    loop {
+        match get_user_input(prompt).to_string().trim().parse::<usize>() {
+            Ok(count) => return count,
+            Err(_) => println!("Invalid input, please enter a number."),
+        }
+    }
+}
+
+async fn prompt_auth_entry() -> Result<GitAuth, String> {
+    let user: Stringy = get_user_input("User");
+    let repo: Stringy = get_user_input("Repo");
+    let branch: Stringy = get_user_input("Branch");
+    let server: GitServer = prompt_server_choice().await; // Prompt for the server
+
+    for (field, value) in [("user", &user), ("repo", &repo), ("branch", &branch)] {
+        if value.to_string().trim().is_empty() {
+            return Err(format!("{} can't be empty", field));
+        }
+    }
+
+    Ok(GitAuth {
+        user,
+        repo,
+        branch,
+        token: None,
+        server,
+    })
+}
+
+// Warn about unreachable entries and let the user decide whether to keep them
+fn confirm_reachable(auth: &GitAuth) -> bool {
+    match verify_remote(auth) {
+        Ok(_) => true,
+        Err(err) => {
+            log!(LogLevel::Warn, "{}-{}@{}: {}", auth.user, auth.repo, auth.branch, err);
+            let answer: String = get_user_input("Keep this entry anyway? (y/n): ").to_string();
+            answer.trim().eq_ignore_ascii_case("y")
+        }
+    }
+}
+
+// Collect a batch of entries, bad entries are skipped or abort the batch depending on the policy
+async fn prompt_auth_entries(prompt: &str, cli_config: &CliConfig, verify: bool) -> Option<Vec<GitAuth>> {
+    let num_instances: usize = prompt_count(prompt);
+    let mut entries: Vec<GitAuth> = Vec::with_capacity(num_instances);
+
+    for i in 0..num_instances {
+        println!("Enter details for GitAuth instance {}", i + 1);
+
+        let mut entry = prompt_auth_entry().await;
+        // unreachable entries the user doesn't want to keep are entered again
+        while let Ok(auth) = &entry {
+            if !verify || confirm_reachable(auth) {
+                break;
+            }
+            println!("Re-enter details for GitAuth instance {}", i + 1);
+            entry = prompt_auth_entry().await;
+        }
+
+        match entry {
+            Ok(auth) => entries.push(auth),
+            Err(err) if cli_config.fail_fast => {
+                log!(LogLevel::Error, "Entry {} is invalid: {}, aborting", i + 1, err);
+                return None;
+            }
+            Err(err) => {
+                log!(LogLevel::Warn, "Entry {} is invalid: {}, skipping it", i + 1, err);
+            }
+        }
+    }
+
+    Some(entries)
+}
+
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     // load the data
     let config = get_config();
+    let cli_config = get_cli_config();
     let mut git_credentials = match get_git_credentials(&config).await {
         Ok(data) => data,
         Err(err) => {
@@ -95,7 +454,7 @@ async fn main() {
                 LogLevel::Warn,
                 "Couldn't load existing credentials bootstrapping"
             );
-            GitCredentials::bootstrap_git_credentials().await.unwrap()
+            bootstrap_credentials().await
         }
     };
 
@@ -103,6 +462,10 @@ async fn main() {
         log!(LogLevel::Info, "{}", config)
     }
 
+    if let Some(command) = cli.command {
+        std::process::exit(run_command(command, &config, git_credentials, !cli.no_verify).await)
+    }
+
     println!("1. View stored git credentials");
     println!("2. Create new git credential file");
     println!("3. Append data to git credential file");
@@ -122,29 +485,20 @@ async fn main() {
             }
             "2" => {
                 log!(LogLevel::Info, "Creating new git credential file");
-                let mut git_creds = GitCredentials::bootstrap_git_credentials().await.unwrap();
-
-                let num_instances: usize =
-                    get_user_input("Enter the number of GitAuth instances to create: ")
-                        .parse()
-                        .expect("Invalid input");
-
-                for i in 0..num_instances {
-                    println!("Enter details for GitAuth instance {}", i + 1);
-
-                    let user: Stringy = get_user_input("User");
-                    let repo: Stringy = get_user_input("Repo");
-                    let branch: Stringy = get_user_input("Branch");
-                    let server: GitServer = prompt_server_choice().await; // Prompt for the server
-
-                    let auth = GitAuth {
-                        user,
-                        repo,
-                        branch,
-                        token: None,
-                        server,
-                    };
+                let mut git_creds = bootstrap_credentials().await;
+
+                let entries = match prompt_auth_entries(
+                    "Enter the number of GitAuth instances to create: ",
+                    &cli_config,
+                    !cli.no_verify,
+                )
+                .await
+                {
+                    Some(entries) => entries,
+                    None => std::process::exit(1),
+                };
 
+                for auth in entries {
                     git_creds.add_auth(auth);
                 }
 
@@ -163,27 +517,18 @@ async fn main() {
             "3" => {
                 log!(LogLevel::Info, "Appending to git credential file");
 
-                let num_instances: usize =
-                    get_user_input("Enter the number of GitAuth instances to add: ")
-                        .parse()
-                        .expect("Invalid input");
-
-                for i in 0..num_instances {
-                    println!("Enter details for GitAuth instance {}", i + 1);
-
-                    let user: Stringy = get_user_input("User");
-                    let repo: Stringy = get_user_input("Repo");
-                    let branch: Stringy = get_user_input("Branch");
-                    let server: GitServer = prompt_server_choice().await; // Prompt for the server
-
-                    let auth = GitAuth {
-                        user,
-                        repo,
-                        branch,
-                        token: None,
-                        server,
-                    };
+                let entries = match prompt_auth_entries(
+                    "Enter the number of GitAuth instances to add: ",
+                    &cli_config,
+                    !cli.no_verify,
+                )
+                .await
+                {
+                    Some(entries) => entries,
+                    None => std::process::exit(1),
+                };
 
+                for auth in entries {
                     git_credentials.add_auth(auth);
                 }
 
@@ -212,10 +557,22 @@ async fn main() {
                     options.push(entry);
                 }
 
-                let mut num = get_user_selection(&options);
-                num -= 1; // to align with the 0 starting index
+                // to align with the 0 starting index
+                let num = match get_user_selection(&options).checked_sub(1) {
+                    Some(num) => num,
+                    None => {
+                        log!(LogLevel::Error, "Invalid selection");
+                        std::process::exit(1)
+                    }
+                };
 
-                let new_credentials = git_credentials.delete_item(num).await.unwrap();
+                let new_credentials = match git_credentials.delete_item(num).await {
+                    Ok(credentials) => credentials,
+                    Err(err) => {
+                        log!(LogLevel::Error, "Couldn't delete entry: {}", err);
+                        std::process::exit(1)
+                    }
+                };
 
                 let git_path = match config.git {
                     Some(data) => PathType::Content(data.credentials_file),